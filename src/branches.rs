@@ -0,0 +1,154 @@
+//! Retention of artifacts reachable from other recently active git branches.
+use crate::{collect::Reachable, ondisk};
+use std::{
+    collections::{BTreeSet, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Directory in a profile directory containing fingerprint directories reachable
+/// from each branch, one file per branch.
+const BRANCHES_DIR: &str = ".cargo-gc-branches";
+
+/// The current branch of the git repository containing `dir`, or `None` if detached
+/// or not in a repository.
+pub fn current_branch(dir: &Path) -> Option<String> {
+    let branch = git(dir, &["rev-parse", "--abbrev-ref", "HEAD"])?;
+    if branch == "HEAD" {
+        return None;
+    }
+    Some(branch)
+}
+
+/// Record reachable fingerprint directories of `branch` in the profile directory `dir`.
+pub fn record(dir: &Path, branch: &str, fingerprints: &HashSet<String>) -> io::Result<()> {
+    let records_dir = dir.join(BRANCHES_DIR);
+    fs::create_dir_all(&records_dir)?;
+    let mut names = fingerprints.iter().collect::<Vec<_>>();
+    names.sort();
+    let content = names
+        .into_iter()
+        .map(|name| format!("{}\n", name))
+        .collect::<String>();
+    fs::write(records_dir.join(escape(branch)), content)
+}
+
+/// Keep builds recorded for branches other than `current` with commits within `max_age`,
+/// and return names of these branches. Records of other branches are added to `stale`,
+/// to be removed after the GC.
+pub fn keep_active(
+    dir: &Path,
+    repo: &Path,
+    current: Option<&str>,
+    max_age: Duration,
+    reachable: &mut Reachable,
+    stale: &mut BTreeSet<PathBuf>,
+) -> io::Result<Vec<String>> {
+    let records_dir = dir.join(BRANCHES_DIR);
+    if !records_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(&records_dir)? {
+        let entry = entry?;
+        let branch = match entry.file_name().to_str() {
+            Some(name) => unescape(name),
+            None => continue,
+        };
+        if Some(&*branch) == current {
+            continue;
+        }
+        let is_active = last_commit_time(repo, &branch)
+            .map_or(false, |time| time + max_age >= SystemTime::now());
+        if !is_active {
+            tracing::debug!("Dropping record of inactive branch {}", branch);
+            stale.insert(entry.path());
+            continue;
+        }
+        for name in fs::read_to_string(entry.path())?.lines() {
+            reachable.keep_build(name);
+        }
+        ret.push(branch);
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Metadata hashes of builds recorded only for branches no longer existing in `repo`,
+/// and not in `reachable`. Records of these branches are added to `stale`, to be removed
+/// after the GC.
+pub fn deleted_only(
+    dir: &Path,
+    repo: &Path,
+    reachable: &Reachable,
+    stale: &mut BTreeSet<PathBuf>,
+) -> io::Result<HashSet<String>> {
+    let records_dir = dir.join(BRANCHES_DIR);
+    if !records_dir.is_dir() {
+        return Ok(HashSet::new());
+    }
+    let mut kept = reachable.fingerprints.clone();
+    let mut deleted = Vec::new();
+    for entry in fs::read_dir(&records_dir)? {
+        let entry = entry?;
+        let branch = match entry.file_name().to_str() {
+            Some(name) => unescape(name),
+            None => continue,
+        };
+        let names = fs::read_to_string(entry.path())?
+            .lines()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+        let refname = format!("refs/heads/{}", branch);
+        if git(repo, &["rev-parse", "--verify", "--quiet", &refname]).is_some() {
+            kept.extend(names);
+        } else {
+            tracing::debug!("Branch {} is deleted", branch);
+            deleted.push((entry.path(), names));
+        }
+    }
+
+    let mut ret = HashSet::new();
+    for (path, names) in deleted {
+        for name in names {
+            if kept.contains(&name) {
+                continue;
+            }
+            if let Some((_, hash)) = ondisk::split_hash(&name) {
+                ret.insert(hash.to_owned());
+            }
+        }
+        stale.insert(path);
+    }
+    Ok(ret)
+}
+
+fn last_commit_time(repo: &Path, branch: &str) -> Option<SystemTime> {
+    let refname = format!("refs/heads/{}", branch);
+    let secs = git(repo, &["log", "-1", "--format=%ct", &refname, "--"])?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?))
+}
+
+pub fn git(dir: &Path, args: &[&str]) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
+
+/// Branch names may contain `/`. Escape it to be used as a file name.
+fn escape(branch: &str) -> String {
+    branch.replace('%', "%25").replace('/', "%2F")
+}
+
+fn unescape(name: &str) -> String {
+    name.replace("%2F", "/").replace("%25", "%")
+}
@@ -1,75 +1,880 @@
-use anyhow::Context as _;
+use crate::{ondisk, timing};
+use anyhow::{bail, Context as _};
 use cargo::{
     core::{
-        compiler::{BuildConfig, CompileMode, Context, CrateType, FileFlavor, UnitInterner},
+        compiler::{
+            BuildConfig, CompileMode, Context, CrateType, FileFlavor, FileType, Unit, UnitInterner,
+        },
         Workspace,
     },
-    ops::{create_bcx, CompileFilter, CompileOptions, Packages},
+    ops::{create_bcx, load_pkg_lockfile, CompileFilter, CompileOptions, Packages},
+    util::toml::TomlProfile,
     CargoResult, Config,
 };
-use std::collections::HashSet;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Instant, SystemTime},
+};
+use structopt::clap::arg_enum;
 
 #[derive(Default, Debug)]
 pub struct Reachable {
     pub fingerprints: HashSet<String>,
     pub builds: HashSet<String>,
+    /// Directories in `build` used to compile build scripts.
+    pub build_compiles: HashSet<String>,
+    /// Directories in `build` used to run build scripts.
+    pub build_runs: HashSet<String>,
     pub deps: HashSet<String>,
     pub uplifts: HashSet<String>,
+    /// File stems in `deps` whose unpacked split debug info objects are kept.
+    pub split_debuginfo: HashSet<String>,
+    /// File stems in `deps` whose files are all kept.
+    pub dep_stems: HashSet<String>,
+    /// Metadata hashes whose files in `deps` are all kept.
+    pub dep_hashes: HashSet<String>,
+    /// Crate names of bench targets.
+    pub benches: HashSet<String>,
+    /// Directories of doctest binaries persisted by rustdoc `--persist-doctests`.
+    pub doctests: HashSet<PathBuf>,
+    /// Path dependencies outside the workspace root. Their metadata hashes depend on
+    /// the path relative to the workspace root, which is reproduced as long as neither moves.
+    pub external: BTreeSet<String>,
+    /// `(fingerprint directory, target name)` of reachable units.
+    pub units: Vec<(String, String)>,
+    /// Reachable units with dependency edges, for size attribution.
+    pub graph: Vec<UnitNode>,
+    /// If set, only artifacts with these metadata hashes are subject to removal,
+    /// and uplifted files are left untouched.
+    pub scope: Option<HashSet<String>>,
+    /// Metadata hashes of builds of packages no longer in the lock file.
+    pub orphans: HashSet<String>,
+    /// Whether all artifacts in scope are removed on request, regardless of reachability.
+    pub purged: bool,
+    /// Whether uplifted files in the profile directory are left untouched.
+    pub preserve_uplifted: bool,
+    /// Whether `.rmeta` files in `deps` are removed when the `.rlib` of the same unit exists.
+    pub aggressive_rmeta: bool,
+    /// Extensions of files post-processed from uplifted binaries, like `foo.hex` of `foo`,
+    /// which are kept along with them.
+    pub post_process_extensions: Vec<String>,
+}
+
+/// Extensions of files commonly generated from binaries by `cargo objcopy` or linker scripts
+/// in embedded workflows.
+pub const POST_PROCESS_EXTENSIONS: &[&str] = &["bin", "hex", "elf", "uf2", "srec", "lst", "map"];
+
+#[derive(Debug)]
+pub struct UnitNode {
+    pub package_id: String,
+    pub name: String,
+    pub version: String,
+    pub features: Vec<String>,
+    /// Whether it is a unit of a workspace member requested directly.
+    pub is_root: bool,
+    /// Directory in `.fingerprint`, and in `build` for build scripts.
+    pub pkg_dir: String,
+    pub is_custom_build: bool,
+    pub is_proc_macro: bool,
+    /// Whether it is of `CompileKind::Host`, which all units are without `--target`.
+    pub is_host_kind: bool,
+    pub target_name: String,
+    /// Root directory of the package, which paths of build dependencies are relative to.
+    pub pkg_root: PathBuf,
+    /// RUSTFLAGS, or RUSTDOCFLAGS of documentation units.
+    pub rustflags: Vec<String>,
+    /// Output files in `deps`.
+    pub deps_files: Vec<String>,
+    /// Indices of dependency units in `Reachable::graph`.
+    pub deps: Vec<usize>,
 }
 
+impl Reachable {
+    /// Check whether on-disk fingerprints of the profile directory `dir` correspond to
+    /// reachable units, and return descriptions of inconsistencies.
+    pub fn verify(&self, dir: &Path) -> io::Result<Vec<String>> {
+        let fingerprint_dir = dir.join(".fingerprint");
+        let mut problems = Vec::new();
+        let mut found_any = false;
+        for (pkg_dir, target_name) in &self.units {
+            let names = match fs::read_dir(fingerprint_dir.join(pkg_dir)) {
+                Ok(iter) => iter
+                    .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                    .collect::<io::Result<Vec<_>>>()?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            found_any = true;
+            // https://github.com/rust-lang/cargo/blob/0a4ec2917698ee067b257b580698d7ffb8ccbe2f/src/cargo/core/compiler/fingerprint.rs
+            let suffix = format!("-{}", target_name);
+            if !names.iter().any(|name| name.ends_with(&suffix)) {
+                problems.push(format!(
+                    "`.fingerprint/{}` has no fingerprint of target `{}`",
+                    pkg_dir, target_name,
+                ));
+            }
+        }
+        let has_fingerprints =
+            fs::read_dir(&fingerprint_dir).map_or(false, |mut iter| iter.next().is_some());
+        if !found_any && has_fingerprints {
+            problems.push(format!(
+                "None of {} reachable units has a fingerprint in `.fingerprint`",
+                self.units.len(),
+            ));
+        }
+        Ok(problems)
+    }
+
+    /// Packages whose reachable units are all host units, ie. build scripts, proc-macros and
+    /// their dependencies, and packages whose reachable units are all target units. Packages
+    /// with units of both are in neither.
+    ///
+    /// When cross-compiling, units are classified by their `CompileKind`. Otherwise all units
+    /// are of `CompileKind::Host`, and they are classified by whether they are reachable from
+    /// build scripts or proc-macros, or from other roots.
+    pub fn host_and_target_packages(&self) -> (HashSet<String>, HashSet<String>) {
+        let is_host_root = |node: &UnitNode| node.is_custom_build || node.is_proc_macro;
+        let mark = |roots: Vec<usize>, mark_host: bool| {
+            let mut marked = vec![false; self.graph.len()];
+            let mut stack = roots;
+            while let Some(idx) = stack.pop() {
+                if marked[idx] {
+                    continue;
+                }
+                marked[idx] = true;
+                for &dep in &self.graph[idx].deps {
+                    // Target units stop at build scripts and proc-macros they depend on.
+                    if mark_host || !is_host_root(&self.graph[dep]) {
+                        stack.push(dep);
+                    }
+                }
+            }
+            marked
+        };
+        let is_cross = self.graph.iter().any(|node| !node.is_host_kind);
+        let indices = 0..self.graph.len();
+        let host = mark(
+            indices
+                .clone()
+                .filter(|&idx| is_host_root(&self.graph[idx]))
+                .collect(),
+            true,
+        );
+        let target = mark(
+            indices
+                .filter(|&idx| self.graph[idx].is_root && !is_host_root(&self.graph[idx]))
+                .collect(),
+            false,
+        );
+
+        // Package name -> (has host units, has target units)
+        let mut kinds = HashMap::<&str, (bool, bool)>::new();
+        for (idx, node) in self.graph.iter().enumerate() {
+            let (is_host, is_target) = if is_cross {
+                (node.is_host_kind, !node.is_host_kind)
+            } else {
+                (host[idx], target[idx])
+            };
+            let entry = kinds.entry(&node.name).or_default();
+            entry.0 |= is_host;
+            entry.1 |= is_target;
+        }
+        let mut host_pkgs = HashSet::new();
+        let mut target_pkgs = HashSet::new();
+        for (name, kind) in kinds {
+            match kind {
+                (true, false) => host_pkgs.insert(name.to_owned()),
+                (false, true) => target_pkgs.insert(name.to_owned()),
+                _ => false,
+            };
+        }
+        (host_pkgs, target_pkgs)
+    }
+
+    /// Whether artifacts with metadata hash `hash` are kept, by reachable units or retention.
+    pub fn keeps_hash(&self, hash: &str) -> bool {
+        self.dep_hashes.contains(hash)
+            || self
+                .fingerprints
+                .iter()
+                .any(|name| ondisk::split_hash(name).map_or(false, |(_, h)| h == hash))
+    }
+
+    /// Restrict removal to artifacts with metadata hashes in `hashes`.
+    pub fn restrict(&mut self, hashes: HashSet<String>) {
+        self.scope = Some(match self.scope.take() {
+            Some(scope) => scope.intersection(&hashes).cloned().collect(),
+            None => hashes,
+        });
+    }
+
+    /// Whether an entry in `.fingerprint`, `build` or `deps` is subject to removal.
+    pub fn in_scope(&self, name: &str) -> bool {
+        match &self.scope {
+            None => true,
+            Some(hashes) => name
+                .split('.')
+                .next()
+                .and_then(ondisk::split_hash)
+                .map_or(false, |(_, hash)| hashes.contains(hash)),
+        }
+    }
+
+    /// Why an entry in `.fingerprint`, `build` or `deps` which is not kept is removed.
+    pub fn removal_reason(&self, name: &str) -> &'static str {
+        if self.purged {
+            return "purged package";
+        }
+        match name.split('.').next().and_then(ondisk::split_hash) {
+            Some((_, hash)) if self.orphans.contains(hash) => "package not in the lock file",
+            Some(_) => "no reachable unit has this metadata hash",
+            None => "no reachable unit has this name",
+        }
+    }
+
+    /// Whether an entry in `deps` should be kept.
+    pub fn keeps_dep(&self, name: &str) -> bool {
+        self.deps.contains(name)
+            || name
+                .split('.')
+                .next()
+                .map_or(false, |stem| self.dep_stems.contains(stem))
+            || name
+                .split('.')
+                .next()
+                .and_then(ondisk::split_hash)
+                .map_or(false, |(_, hash)| self.dep_hashes.contains(hash))
+            // rustdoc scrape-examples output `{pkg}-{hash}.examples` of a reachable unit.
+            || name
+                .strip_suffix(".examples")
+                .map_or(false, |key| self.fingerprints.contains(key))
+            // `{stem}.{cgu}.rcgu.dwo`, or `.o` on macOS, from unpacked split debug info.
+            || (name.ends_with(".dwo") || name.ends_with(".o"))
+                && name
+                    .split('.')
+                    .next()
+                    .map_or(false, |stem| self.split_debuginfo.contains(stem))
+    }
+
+    /// Keep the latest unreachable executable of each bench target in `deps` of
+    /// the profile directory `dir`, which is likely used to record saved criterion baselines.
+    pub fn keep_previous_benches(&mut self, dir: &Path) -> io::Result<()> {
+        let deps_dir = dir.join("deps");
+        if !deps_dir.is_dir() {
+            return Ok(());
+        }
+        let mut latest = HashMap::<String, (SystemTime, String)>::new();
+        for entry in fs::read_dir(deps_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let stem = name.strip_suffix(".exe").unwrap_or(&name);
+            let crate_name = match stem.rfind('-') {
+                Some(idx) => &stem[..idx],
+                None => continue,
+            };
+            if stem.contains('.')
+                || self.deps.contains(&name)
+                || !self.benches.contains(crate_name)
+                || !entry.file_type()?.is_file()
+            {
+                continue;
+            }
+            let mtime = entry.metadata()?.modified()?;
+            match latest.get(crate_name) {
+                Some((prev, _)) if *prev >= mtime => {}
+                _ => {
+                    latest.insert(crate_name.to_owned(), (mtime, stem.to_owned()));
+                }
+            }
+        }
+        self.dep_stems
+            .extend(latest.into_iter().map(|(_, (_, stem))| stem));
+        Ok(())
+    }
+
+    /// Keep artifacts of the latest `count` builds of each package in the profile directory
+    /// `dir`, by modification times of their fingerprints, whether reachable or not.
+    ///
+    /// A build of a package has a fingerprint directory for each kind of units, eg. the
+    /// library, the build script and its run, so fingerprint directories only compete with
+    /// those of the same units.
+    pub fn keep_latest(&mut self, dir: &Path, count: usize) -> io::Result<()> {
+        let fingerprint_dir = dir.join(".fingerprint");
+        // (package name, units) -> [(last modified time, fingerprint directory)]
+        let mut variants = HashMap::<(String, Vec<String>), Vec<(SystemTime, String)>>::new();
+        for name in ondisk::list_names(&fingerprint_dir)? {
+            if let Some((pkg, _)) = ondisk::split_hash(&name) {
+                let path = fingerprint_dir.join(&name);
+                // Each unit has a fingerprint `{kind}-{target}.json`, eg. `lib-foo.json` or
+                // `run-build-script-build-script-build.json`.
+                let mut units = ondisk::list_names(&path)?
+                    .into_iter()
+                    .filter_map(|file| file.strip_suffix(".json").map(str::to_owned))
+                    .collect::<Vec<_>>();
+                units.sort();
+                let mtime = ondisk::latest_mtime(&path)?;
+                variants
+                    .entry((pkg.to_owned(), units))
+                    .or_default()
+                    .push((mtime, name.clone()));
+            }
+        }
+        for (_, mut variants) in variants {
+            variants.sort_unstable_by(|a, b| b.cmp(a));
+            for (_, name) in variants.into_iter().take(count) {
+                self.keep_build(&name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Keep uplifted binaries in the profile directory `dir` even if unreachable, and the
+    /// latest `count` builds of each of them in `deps`.
+    pub fn keep_uplifted(&mut self, dir: &Path, count: usize) -> io::Result<()> {
+        if count == 0 {
+            return Ok(());
+        }
+        let deps_dir = dir.join("deps");
+        let deps = ondisk::list_names(&deps_dir)?;
+        for name in ondisk::list_names(dir)? {
+            if !ondisk::is_executable(&dir.join(&name)) {
+                continue;
+            }
+            // Binaries in `deps` are named after the crate name.
+            let crate_name = name.strip_suffix(".exe").unwrap_or(&name).replace('-', "_");
+            let mut builds = Vec::new();
+            for file in &deps {
+                let stem = file.strip_suffix(".exe").unwrap_or(file);
+                match ondisk::split_hash(stem) {
+                    Some((bin, _)) if bin == crate_name => {}
+                    _ => continue,
+                }
+                let path = deps_dir.join(file);
+                if ondisk::is_executable(&path) {
+                    builds.push((fs::metadata(&path)?.modified()?, stem.to_owned()));
+                }
+            }
+            builds.sort_unstable_by(|a, b| b.cmp(a));
+            self.dep_stems
+                .extend(builds.into_iter().take(count).map(|(_, stem)| stem));
+            self.uplifts.insert(name);
+        }
+        Ok(())
+    }
+
+    /// Keep all artifacts of the build with fingerprint directory `{pkg}-{hash}`.
+    pub fn keep_build(&mut self, name: &str) {
+        if let Some((_, hash)) = ondisk::split_hash(name) {
+            self.dep_hashes.insert(hash.to_owned());
+        }
+        self.builds.insert(name.to_owned());
+        self.fingerprints.insert(name.to_owned());
+    }
+
+    /// Whether an entry in the profile directory should be kept.
+    pub fn keeps_uplift(&self, name: &str) -> bool {
+        self.uplifts.contains(name)
+            // The dSYM bundle of a kept binary.
+            || name
+                .strip_suffix(".dSYM")
+                .map_or(false, |bin| self.uplifts.contains(bin))
+            // Outputs post-processed from a kept binary.
+            || self.post_process_extensions.iter().any(|ext| {
+                name.strip_suffix(&**ext)
+                    .and_then(|name| name.strip_suffix('.'))
+                    .map_or(false, |bin| self.uplifts.contains(bin))
+            })
+    }
+}
+
+/// Separate debug info produced alongside linked outputs.
+enum DebugInfo {
+    /// `foo.dSYM` bundle on macOS.
+    Dsym,
+    /// `foo.pdb` on windows-msvc.
+    Pdb,
+    /// `foo.dwp` package with `split-debuginfo = "packed"` on other platforms.
+    Dwp,
+}
+
+impl DebugInfo {
+    /// https://github.com/rust-lang/cargo/blob/0a4ec2917698ee067b257b580698d7ffb8ccbe2f/src/cargo/core/compiler/build_context/target_info.rs
+    fn of(triple: &str, split_debuginfo: Option<&str>, file_type: &FileType) -> Option<Self> {
+        let linked = file_type.flavor == FileFlavor::Normal
+            && matches!(
+                file_type.crate_type,
+                Some(CrateType::Bin)
+                    | Some(CrateType::Dylib)
+                    | Some(CrateType::Cdylib)
+                    | Some(CrateType::ProcMacro)
+            );
+        if !linked {
+            None
+        } else if triple.contains("-apple-") {
+            // Debug info is left in object files when unpacked.
+            match split_debuginfo {
+                Some("unpacked") => None,
+                _ => Some(Self::Dsym),
+            }
+        } else if triple.ends_with("-msvc") {
+            Some(Self::Pdb)
+        } else {
+            match split_debuginfo {
+                Some("packed") => Some(Self::Dwp),
+                _ => None,
+            }
+        }
+    }
+
+    /// The debug info file name of an output file. Uplifted PDBs and DWPs have hyphens replaced.
+    fn filename(&self, output: &str, uplifted: bool) -> String {
+        let ext = match self {
+            Self::Dsym => return format!("{}.dSYM", output),
+            Self::Pdb => "pdb",
+            Self::Dwp => "dwp",
+        };
+        if uplifted {
+            format!("{}.{}", file_stem(output).replace('-', "_"), ext)
+        } else {
+            format!("{}.{}", file_stem(output), ext)
+        }
+    }
+}
+
+/// The uplifted file and its companions, eg. `foo.exe`, `foo.pdb` and `foo.d` on windows-msvc,
+/// `foo` and `foo.dSYM` on macOS.
+fn uplift_files(
+    file_type: &FileType,
+    uplift_name: &str,
+    debuginfo: Option<&DebugInfo>,
+) -> Vec<String> {
+    let mut ret = vec![uplift_name.to_owned()];
+    // Dep-info is written for each uplifted output except debug info and auxiliary files.
+    // https://github.com/rust-lang/cargo/blob/0a4ec2917698ee067b257b580698d7ffb8ccbe2f/src/cargo/core/compiler/output_depinfo.rs
+    if !matches!(
+        file_type.flavor,
+        FileFlavor::DebugInfo | FileFlavor::Auxiliary
+    ) {
+        ret.push(format!("{}.d", file_stem(uplift_name)));
+    }
+    if let Some(debuginfo) = debuginfo {
+        ret.push(debuginfo.filename(uplift_name, true));
+    }
+    ret
+}
+
+/// Files written alongside an output in `deps` but not listed by cargo.
+fn companions(triple: &str, filename: &str) -> Vec<String> {
+    let mut ret = Vec::new();
+    // The MSVC linker writes an export file next to the import library.
+    if let Some(stem) = filename.strip_suffix(".dll.lib") {
+        ret.push(format!("{}.dll.exp", stem));
+    }
+    // Source maps of emscripten, or of wasm-bindgen post-processing in place, and the
+    // JavaScript glue generated next to the module.
+    if let Some(stem) = filename.strip_suffix(".wasm") {
+        if triple.starts_with("wasm32-") {
+            ret.push(format!("{}.map", filename));
+            ret.push(format!("{}.js", stem));
+        }
+    }
+    ret
+}
+
+/// Find the value of `--persist-doctests` in rustdoc flags.
+fn persist_doctests_dir(rustdocflags: &[String]) -> Option<&str> {
+    let mut iter = rustdocflags.iter();
+    while let Some(flag) = iter.next() {
+        if flag == "--persist-doctests" {
+            return iter.next().map(|s| &**s);
+        }
+        if let Some(dir) = flag.strip_prefix("--persist-doctests=") {
+            return Some(dir);
+        }
+    }
+    None
+}
+
+fn file_stem(name: &str) -> &str {
+    &name[..name.rfind('.').unwrap_or(name.len())]
+}
+
+/// Names of all packages in lock files of `workspaces`, or `None` if any of them
+/// has no lock file.
+pub fn locked_packages(workspaces: &[Workspace]) -> CargoResult<Option<HashSet<String>>> {
+    let mut ret = HashSet::new();
+    for ws in workspaces {
+        match load_pkg_lockfile(ws)? {
+            Some(resolve) => ret.extend(resolve.iter().map(|id| id.name().to_string())),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(ret))
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Mode {
+        Build,
+        Check,
+        Test,
+        Bench,
+        Doc,
+    }
+}
+
+impl Mode {
+    /// The selectable mode of units in `mode`. Build script runs are never roots.
+    fn of(mode: CompileMode) -> Option<Self> {
+        match mode {
+            CompileMode::Build => Some(Self::Build),
+            CompileMode::Check { .. } => Some(Self::Check),
+            CompileMode::Test => Some(Self::Test),
+            CompileMode::Bench => Some(Self::Bench),
+            CompileMode::Doc { .. } | CompileMode::Doctest => Some(Self::Doc),
+            CompileMode::RunCustomBuild => None,
+        }
+    }
+}
+
+/// Built-in profiles with their output directories. `test` and `bench` are built into
+/// those of `dev` and `release`.
+const BUILTIN_PROFILES: &[(&str, &str)] = &[
+    ("dev", "debug"),
+    ("release", "release"),
+    ("test", "debug"),
+    ("bench", "release"),
+];
+
+/// Profiles to collect with their output directory names: `dev`, `release`, and custom
+/// profiles defined in the manifest of `ws` or in cargo configurations, which are built into
+/// directories of their own names.
+pub fn profiles(ws: &Workspace) -> CargoResult<Vec<(String, String)>> {
+    // Profile name -> the profile it inherits.
+    let mut inherits = BTreeMap::<String, Option<String>>::new();
+    if let Some(profiles) = ws.profiles() {
+        for (name, profile) in profiles.get_all() {
+            inherits.insert(name.to_string(), profile.inherits.map(|p| p.to_string()));
+        }
+    }
+    // `.cargo/config` overrides `Cargo.toml`.
+    let config_profiles = ws
+        .config()
+        .get::<Option<BTreeMap<String, TomlProfile>>>("profile")?;
+    for (name, profile) in config_profiles.into_iter().flatten() {
+        match profile.inherits {
+            Some(parent) => {
+                inherits.insert(name, Some(parent.to_string()));
+            }
+            None => {
+                inherits.entry(name).or_insert(None);
+            }
+        }
+    }
+
+    let is_builtin = |name: &str| BUILTIN_PROFILES.iter().any(|&(p, _)| p == name);
+    let mut ret = vec![
+        ("dev".to_owned(), "debug".to_owned()),
+        ("release".to_owned(), "release".to_owned()),
+    ];
+    for name in inherits.keys() {
+        // The obsolete `doc` profile is ignored by cargo.
+        if is_builtin(name) || name == "doc" {
+            continue;
+        }
+        // Cargo rejects custom profiles whose `inherits` chain does not reach a built-in one.
+        let mut seen = HashSet::new();
+        let mut cur = name;
+        let valid = loop {
+            if !seen.insert(cur) {
+                break false;
+            }
+            match inherits.get(cur) {
+                Some(Some(parent)) if is_builtin(parent) => break true,
+                Some(Some(parent)) => cur = parent,
+                _ => break false,
+            }
+        };
+        if valid {
+            ret.push((name.clone(), name.clone()));
+        } else {
+            tracing::debug!("Skipping profile `{}` without a valid `inherits`", name);
+        }
+    }
+    Ok(ret)
+}
+
+/// Packages and features to collect units of.
+#[derive(Debug, Default)]
+pub struct Selection {
+    /// Select all members instead of default members.
+    pub all_members: bool,
+    /// Only keep units reachable from roots in these modes. All modes if empty.
+    pub modes: Vec<Mode>,
+    pub features: Vec<String>,
+    pub no_default_features: bool,
+    /// Check `rust-version` of packages against rustc, like cargo without `--ignore-rust-version`.
+    pub honor_rust_version: bool,
+}
+
+impl Selection {
+    /// All features are selected unless features are configured explicitly.
+    fn all_features(&self) -> bool {
+        self.features.is_empty() && !self.no_default_features
+    }
+}
+
+/// Packages in the lock file of `ws` whose sources are not available in cargo home,
+/// which would have to be downloaded to collect units.
+pub fn missing_sources(ws: &Workspace) -> CargoResult<Vec<String>> {
+    let resolve = match load_pkg_lockfile(ws)? {
+        Some(resolve) => resolve,
+        None => bail!("`Cargo.lock` is missing and would have to be generated"),
+    };
+    let home = ws.config().home().clone().into_path_unlocked();
+    let subdirs = |dir: PathBuf| -> Vec<PathBuf> {
+        fs::read_dir(dir).map_or(Vec::new(), |iter| {
+            iter.filter_map(|entry| Some(entry.ok()?.path())).collect()
+        })
+    };
+    // `registry/src/<index>/<name>-<version>`
+    let registries = subdirs(home.join("registry").join("src"));
+    // `git/checkouts/<repo>/<short revision>`
+    let checkouts = subdirs(home.join("git").join("checkouts"))
+        .into_iter()
+        .flat_map(subdirs)
+        .collect::<Vec<_>>();
+
+    let mut ret = Vec::new();
+    for id in resolve.iter() {
+        let source_id = id.source_id();
+        let available = if source_id.is_registry() {
+            let name = format!("{}-{}", id.name(), id.version());
+            registries
+                .iter()
+                .any(|dir| dir.join(&name).join("Cargo.toml").is_file())
+        } else if source_id.is_git() {
+            source_id.precise().map_or(false, |rev| {
+                checkouts.iter().any(|dir| {
+                    dir.file_name()
+                        .and_then(|name| name.to_str())
+                        .map_or(false, |name| rev.starts_with(name))
+                })
+            })
+        } else {
+            true
+        };
+        if !available {
+            ret.push(id.to_string());
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Collect reachable artifacts of `ws` for `profile` on `targets` (or the host if empty).
+/// The unit graph of all targets covers every compile mode, like `Test` and `Check`, in one
+/// `BuildContext`. Only `test` or `bench` profiles, if defined, get their own `BuildContext`.
 pub fn collect_workspace_units(
     config: &Config,
     ws: &Workspace,
     targets: &[String],
     profile: &str,
+    selection: &Selection,
     out: &mut Reachable,
 ) -> CargoResult<()> {
     // https://github.com/rust-lang/cargo/blob/0a4ec2917698ee067b257b580698d7ffb8ccbe2f/src/cargo/util/command_prelude.rs#L361
-    let spec = Packages::All;
+    // Like cargo, only `default-members` are selected unless `--workspace` is passed.
+    let spec = if selection.all_members {
+        Packages::All
+    } else {
+        Packages::Default
+    };
     let jobs = None;
     let compile_mode = CompileMode::Build; // Already select all targets below.
-    let mut build_config = BuildConfig::new(&config, jobs, targets, compile_mode)?;
-    build_config.requested_profile = profile.into();
-
-    let compile_opts = CompileOptions {
-        build_config,
-        features: Vec::new(),
-        all_features: true,
-        no_default_features: false,
-        spec: spec.clone(),
-        filter: CompileFilter::new_all_targets(),
-        target_rustdoc_args: None,
-        target_rustc_args: None,
-        local_rustdoc_args: None,
-        rustdoc_document_private_items: false,
-        honor_rust_version: false,
-    };
 
-    collect_units(ws, &compile_opts, out)?;
+    // `cargo test` and `cargo bench` request `test` and `bench`, which are built into the
+    // same directories as `dev` and `release`. They inherit the latter and produce the same
+    // hashes unless overridden.
+    let mut requested = vec![profile];
+    match profile {
+        "dev" if is_defined(ws, "test")? => requested.push("test"),
+        "release" if is_defined(ws, "bench")? => requested.push("bench"),
+        _ => {}
+    }
+    for profile in requested {
+        let mut build_config = BuildConfig::new(&config, jobs, targets, compile_mode)?;
+        build_config.requested_profile = profile.into();
+
+        let compile_opts = CompileOptions {
+            build_config,
+            // Targets with unsatisfied `required-features` are skipped by cargo itself.
+            features: selection.features.clone(),
+            all_features: selection.all_features(),
+            no_default_features: selection.no_default_features,
+            spec: spec.clone(),
+            filter: CompileFilter::new_all_targets(),
+            target_rustdoc_args: None,
+            target_rustc_args: None,
+            local_rustdoc_args: None,
+            rustdoc_document_private_items: false,
+            honor_rust_version: selection.honor_rust_version,
+        };
+
+        collect_units(ws, &compile_opts, &selection.modes, out)?;
+    }
 
     Ok(())
 }
 
+/// Directories of profiles of `ws` setting `trim-paths` by themselves or by inheritance, in
+/// the manifest or cargo configurations. It is unknown to the cargo library in use, while
+/// toolchains supporting it hash it into metadata, so their artifacts cannot be reproduced.
+pub fn trim_paths_dirs(ws: &Workspace) -> CargoResult<Vec<String>> {
+    // `TomlProfile` of the cargo library drops the unknown key.
+    let manifest = fs::read_to_string(ws.root_manifest())?
+        .parse::<toml::Value>()
+        .ok();
+    let sets_trim_paths = |name: &str| -> CargoResult<bool> {
+        let in_manifest = manifest
+            .as_ref()
+            .and_then(|manifest| manifest.get("profile")?.get(name)?.get("trim-paths"))
+            .is_some();
+        let key = format!("profile.{}.trim-paths", name);
+        Ok(in_manifest || ws.config().get::<Option<toml::Value>>(&key)?.is_some())
+    };
+    let parent = |name: &str| -> CargoResult<Option<String>> {
+        let key = format!("profile.{}.inherits", name);
+        if let Some(parent) = ws.config().get::<Option<String>>(&key)? {
+            return Ok(Some(parent));
+        }
+        let in_manifest = ws
+            .profiles()
+            .and_then(|profiles| profiles.get(name)?.inherits);
+        Ok(match name {
+            "test" => Some("dev".to_owned()),
+            "bench" => Some("release".to_owned()),
+            _ => in_manifest.map(|parent| parent.to_string()),
+        })
+    };
+
+    let mut ret = Vec::new();
+    for (name, dir) in profiles(ws)? {
+        // `test` and `bench` are built into the directories of `dev` and `release`.
+        let mut queue = vec![name.clone()];
+        match &*name {
+            "dev" => queue.push("test".to_owned()),
+            "release" => queue.push("bench".to_owned()),
+            _ => {}
+        }
+        let mut seen = HashSet::new();
+        while let Some(cur) = queue.pop() {
+            if !seen.insert(cur.clone()) {
+                continue;
+            }
+            if sets_trim_paths(&cur)? {
+                ret.push(dir);
+                break;
+            }
+            queue.extend(parent(&cur)?);
+        }
+    }
+    Ok(ret)
+}
+
+/// Whether the profile `name` is defined in the manifest of `ws` or in cargo configurations.
+fn is_defined(ws: &Workspace, name: &str) -> CargoResult<bool> {
+    let in_manifest = ws
+        .profiles()
+        .map_or(false, |profiles| profiles.get(name).is_some());
+    let key = format!("profile.{}", name);
+    Ok(in_manifest || ws.config().get::<Option<TomlProfile>>(&key)?.is_some())
+}
+
 fn collect_units(
     ws: &Workspace,
     compile_opts: &CompileOptions,
+    modes: &[Mode],
     reachable: &mut Reachable,
 ) -> CargoResult<()> {
     let interner = UnitInterner::new();
-    log::debug!("Creating BuildContext");
-    let bcx = create_bcx(ws, compile_opts, &interner).context("Create BuildContext")?;
+    tracing::debug!("Creating BuildContext");
+    // Resolution happens inside `create_bcx` and cannot be timed separately.
+    let phase = format!(
+        "resolve and BuildContext ({})",
+        compile_opts.build_config.requested_profile,
+    );
+    let bcx = tracing::debug_span!("resolve", ws = %ws.root().display())
+        .in_scope(|| timing::measure(&phase, || create_bcx(ws, compile_opts, &interner)))
+        .context("Create BuildContext")?;
 
-    log::debug!("Creating Context");
+    let start = Instant::now();
+    tracing::debug!("Creating Context");
     let mut cx = Context::new(&bcx).context("Create Context")?;
-    log::debug!("Generating lto");
+    tracing::debug!("Generating lto");
     cx.lto = crate::cargo_lto::generate(cx.bcx)?;
-    log::debug!("Preparing units");
+    tracing::debug!("Preparing units");
     cx.prepare_units().context("Prepare units")?;
     let files = cx.files();
+    timing::record("unit preparation", start.elapsed());
+    let start = Instant::now();
 
-    log::debug!("Scanning units");
-    for unit in bcx.unit_graph.keys() {
+    // Doctests run in the package root, where relative paths are resolved.
+    // https://github.com/rust-lang/cargo/blob/0a4ec2917698ee067b257b580698d7ffb8ccbe2f/src/cargo/core/compiler/compilation.rs
+    for unit in bcx.roots.iter() {
+        let info = bcx.target_data.info(unit.kind);
+        if let Some(dir) = persist_doctests_dir(&info.rustdocflags) {
+            reachable.doctests.insert(unit.pkg.root().join(dir));
+        }
+    }
+
+    // Units of unselected roots and their exclusive dependencies are left to be collected.
+    let mut selected = HashSet::new();
+    if !modes.is_empty() {
+        let mut stack = bcx
+            .roots
+            .iter()
+            .filter(|unit| Mode::of(unit.mode).map_or(false, |mode| modes.contains(&mode)))
+            .collect::<Vec<_>>();
+        while let Some(unit) = stack.pop() {
+            if selected.insert(unit) {
+                stack.extend(bcx.unit_graph[unit].iter().map(|dep| &dep.unit));
+            }
+        }
+    }
+
+    let is_selected = |unit: &Unit| modes.is_empty() || selected.contains(&unit);
+
+    let base = reachable.graph.len();
+    let indices = bcx
+        .unit_graph
+        .keys()
+        .filter(|unit| is_selected(unit))
+        .enumerate()
+        .map(|(i, unit)| (unit, base + i))
+        .collect::<HashMap<_, _>>();
+
+    tracing::debug!("Scanning units");
+    for (unit, unit_deps) in bcx.unit_graph.iter() {
+        if !is_selected(unit) {
+            continue;
+        }
+        let mut deps_files = Vec::new();
+        // Profiles of build scripts, proc macros and their dependencies are resolved by cargo
+        // with `build-override` merged when generating the unit graph, so the metadata hash
+        // reproduces that of cargo. The ported LTO computation, which also affects the hash,
+        // treats them as host units like cargo.
         let meta = files.metadata(unit).map(|m| m.to_string());
+        // Outputs are named after the crate name of the target itself, never the extern name
+        // of renamed dependencies like `foo = { package = "bar" }`, which only appears in
+        // `--extern foo=...` of dependent units.
+        let dep_stem = match &meta {
+            Some(meta) => format!("{}-{}", unit.target.crate_name(), meta),
+            None => unit.target.crate_name(),
+        };
+        let split_debuginfo = unit.profile.split_debuginfo.as_deref();
 
         if let CompileMode::Test
         | CompileMode::Build
@@ -78,11 +883,29 @@ fn collect_units(
         {
             let info = bcx.target_data.info(unit.kind);
             let triple = bcx.target_data.short_name(&unit.kind);
-            let (file_types, _unsupported) =
+            // File types of every crate type, including `CrateType::Other`, are queried from
+            // rustc by `--print file-names`.
+            let (file_types, unsupported) =
                 info.rustc_outputs(unit.mode, unit.target.kind(), triple)?;
+            if !unsupported.is_empty() {
+                // Rustc of the probe knows no file names for them. Keep anything of the stem,
+                // in case the rustc building them does.
+                tracing::debug!(
+                    "Crate types {:?} of {} have unknown outputs on {}",
+                    unsupported,
+                    unit.target.name(),
+                    triple,
+                );
+                reachable.dep_stems.insert(dep_stem.clone());
+            }
             for file_type in &file_types {
                 let filename = file_type.output_filename(&unit.target, meta.as_deref());
-                reachable.deps.insert(filename.clone());
+                let debuginfo = DebugInfo::of(triple, split_debuginfo, file_type);
+                if let Some(debuginfo) = &debuginfo {
+                    deps_files.push(debuginfo.filename(&filename, false));
+                }
+                deps_files.extend(companions(triple, &filename));
+                deps_files.push(filename.clone());
 
                 // https://github.com/rust-lang/cargo/blob/6ca27ffc857c7ac658fda14a83dfb4905d742315/src/cargo/core/compiler/context/compilation_files.rs#L334
                 if unit.mode == CompileMode::Build
@@ -93,19 +916,32 @@ fn collect_units(
                         || bcx.roots.contains(unit))
                 {
                     let uplift_name = file_type.uplift_filename(&unit.target);
-                    let stem = &uplift_name[..uplift_name.rfind('.').unwrap_or(uplift_name.len())];
-                    reachable.uplifts.insert(format!("{}.d", stem));
-                    reachable.uplifts.insert(uplift_name);
+                    reachable.uplifts.extend(companions(triple, &uplift_name));
+                    reachable.uplifts.extend(uplift_files(
+                        file_type,
+                        &uplift_name,
+                        debuginfo.as_ref(),
+                    ));
                 }
             }
         }
 
-        reachable.deps.insert(match &meta {
-            Some(meta) => format!("{}-{}.d", unit.target.crate_name(), &meta),
-            None => format!("{}.d", unit.target.crate_name()),
-        });
+        deps_files.push(format!("{}.d", dep_stem));
+        reachable.deps.extend(deps_files.iter().cloned());
+        if split_debuginfo == Some("unpacked") {
+            reachable.split_debuginfo.insert(dep_stem);
+        }
+
+        if unit.target.is_bench() {
+            reachable.benches.insert(unit.target.crate_name());
+        }
 
-        let pkg_name = unit.pkg.package_id().name();
+        let pkg_id = unit.pkg.package_id();
+        if pkg_id.source_id().is_path() && !unit.pkg.root().starts_with(ws.root()) {
+            reachable.external.insert(pkg_id.to_string());
+        }
+
+        let pkg_name = pkg_id.name();
         let pkg_dir = match &meta {
             Some(meta) => format!("{}-{}", pkg_name, meta),
             None => format!("{}-{}", pkg_name, files.target_short_hash(unit)),
@@ -113,9 +949,293 @@ fn collect_units(
 
         if unit.target.is_custom_build() {
             reachable.builds.insert(pkg_dir.clone());
+            if unit.mode.is_run_custom_build() {
+                reachable.build_runs.insert(pkg_dir.clone());
+            } else {
+                reachable.build_compiles.insert(pkg_dir.clone());
+            }
         }
 
+        reachable
+            .units
+            .push((pkg_dir.clone(), unit.target.name().to_owned()));
+
+        reachable.graph.push(UnitNode {
+            package_id: pkg_id.to_string(),
+            name: pkg_id.name().to_string(),
+            version: pkg_id.version().to_string(),
+            features: unit.features.iter().map(|f| f.to_string()).collect(),
+            is_root: bcx.roots.contains(unit),
+            pkg_dir: pkg_dir.clone(),
+            is_custom_build: unit.target.is_custom_build(),
+            is_proc_macro: unit.target.proc_macro(),
+            is_host_kind: unit.kind.is_host(),
+            target_name: unit.target.name().to_owned(),
+            pkg_root: unit.pkg.root().to_owned(),
+            rustflags: {
+                let info = bcx.target_data.info(unit.kind);
+                if unit.mode.is_doc() {
+                    info.rustdocflags.clone()
+                } else {
+                    info.rustflags.clone()
+                }
+            },
+            deps_files,
+            deps: unit_deps.iter().map(|dep| indices[&dep.unit]).collect(),
+        });
+
         reachable.fingerprints.insert(pkg_dir);
     }
+    timing::record("unit scan", start.elapsed());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process::Command};
+
+    fn write_files(dir: &Path, files: &[(&str, String)]) {
+        for (path, content) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+    }
+
+    /// A package with a build script and a proc-macro dependency, whose units are built with
+    /// `[profile.dev.build-override]`.
+    fn write_project(dir: &Path, opt_level: u32) {
+        write_files(
+            dir,
+            &[
+                (
+                    "Cargo.toml",
+                    format!(
+                        r#"[package]
+name = "build-override-test"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+pm = {{ path = "pm" }}
+
+[workspace]
+
+[profile.dev.build-override]
+opt-level = {}
+"#,
+                        opt_level,
+                    ),
+                ),
+                ("build.rs", "fn main() {}\n".to_owned()),
+                ("src/main.rs", "pm::noop!();\nfn main() {}\n".to_owned()),
+                (
+                    "pm/Cargo.toml",
+                    "[package]\nname = \"pm\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n[lib]\nproc-macro = true\n"
+                        .to_owned(),
+                ),
+                (
+                    "pm/src/lib.rs",
+                    "extern crate proc_macro;\n#[proc_macro]\npub fn noop(_: proc_macro::TokenStream) -> proc_macro::TokenStream {\n    Default::default()\n}\n"
+                        .to_owned(),
+                ),
+            ],
+        );
+    }
+
+    /// Build the project in `dir` with the cargo running the tests into `target_dir`, and
+    /// collect its units with the cargo library.
+    fn build_and_collect(dir: &Path, target_dir: &Path) -> Reachable {
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let status = Command::new(cargo)
+            .args(&["build", "--offline", "--quiet"])
+            .current_dir(dir)
+            .env("CARGO_TARGET_DIR", target_dir)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let config = Config::default().unwrap();
+        let ws = Workspace::new(&dir.join("Cargo.toml"), &config).unwrap();
+        let mut reachable = Reachable::default();
+        let selection = Selection::default();
+        collect_workspace_units(&config, &ws, &[], "dev", &selection, &mut reachable).unwrap();
+        reachable
+    }
+
+    /// Every unit built by cargo in `profile_dir` is reproduced with the same hash.
+    fn assert_collected(reachable: &Reachable, profile_dir: &Path) {
+        for name in ondisk::list_names(&profile_dir.join(".fingerprint")).unwrap() {
+            assert!(
+                reachable.fingerprints.contains(&name),
+                "{} built by cargo is not collected",
+                name,
+            );
+        }
+        for name in ondisk::list_names(&profile_dir.join("deps")).unwrap() {
+            assert!(reachable.keeps_dep(&name), "{} in deps is not kept", name);
+        }
+    }
+
+    #[test]
+    fn build_override_hashes() {
+        let dir = env::temp_dir().join(format!("cargo-gc-build-override-{}", std::process::id()));
+        let mut host_dirs = Vec::new();
+        for &opt_level in &[0, 3] {
+            write_project(&dir, opt_level);
+            let target_dir = dir.join(format!("target-{}", opt_level));
+            let reachable = build_and_collect(&dir, &target_dir);
+
+            assert_collected(&reachable, &target_dir.join("debug"));
+
+            host_dirs.push(
+                reachable
+                    .graph
+                    .iter()
+                    .filter(|node| node.is_custom_build || node.is_proc_macro)
+                    .map(|node| node.pkg_dir.clone())
+                    .collect::<BTreeSet<_>>(),
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Hashes of build scripts and proc-macros all move with `build-override`.
+        assert!(host_dirs[0].is_disjoint(&host_dirs[1]), "{:?}", host_dirs);
+    }
+
+    #[test]
+    fn keep_latest_with_build_script() {
+        let dir = env::temp_dir().join(format!("cargo-gc-keep-latest-{}", std::process::id()));
+        let target_dir = dir.join("target");
+        let fingerprint_dir = target_dir.join("debug/.fingerprint");
+        write_project(&dir, 0);
+        build_and_collect(&dir, &target_dir);
+        let first = ondisk::list_names(&fingerprint_dir).unwrap();
+        write_project(&dir, 3);
+        build_and_collect(&dir, &target_dir);
+
+        let mut reachable = Reachable::default();
+        reachable.keep_latest(&target_dir.join("debug"), 1).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The binary, the build script and its run, all of the second build.
+        let kept = reachable
+            .builds
+            .iter()
+            .filter(|name| name.starts_with("build-override-test-"))
+            .collect::<Vec<_>>();
+        assert_eq!(kept.len(), 3, "{:?}", kept);
+        for name in kept {
+            assert!(!first.contains(name), "{} of the first build is kept", name);
+        }
+    }
+
+    #[test]
+    fn multiple_crate_types() {
+        let dir = env::temp_dir().join(format!("cargo-gc-crate-types-{}", std::process::id()));
+        write_files(
+            &dir,
+            &[
+                (
+                    "Cargo.toml",
+                    r#"[package]
+name = "multi"
+version = "0.1.0"
+edition = "2018"
+
+[lib]
+crate-type = ["cdylib", "staticlib"]
+
+[workspace]
+"#
+                    .to_owned(),
+                ),
+                ("src/lib.rs", "pub fn f() {}\n".to_owned()),
+            ],
+        );
+        let target_dir = dir.join("target");
+        let reachable = build_and_collect(&dir, &target_dir);
+        let profile_dir = target_dir.join("debug");
+        let deps = ondisk::list_names(&profile_dir.join("deps")).unwrap();
+        let uplifted = ondisk::list_names(&profile_dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Outputs of both crate types, eg. `libmulti.so` and `libmulti.a`.
+        assert!(deps.len() >= 3, "{:?}", deps);
+        for name in &deps {
+            assert!(reachable.keeps_dep(name), "{} in deps is not kept", name);
+        }
+        let uplifted = uplifted
+            .iter()
+            .filter(|name| name.starts_with("libmulti") || name.starts_with("multi"))
+            .collect::<Vec<_>>();
+        assert!(uplifted.len() >= 2, "{:?}", uplifted);
+        for name in uplifted {
+            assert!(
+                reachable.keeps_uplift(name),
+                "uplifted {} is not kept",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn path_dependency_outside_workspace() {
+        let dir = env::temp_dir().join(format!("cargo-gc-outside-{}", std::process::id()));
+        write_files(
+            &dir,
+            &[
+                (
+                    "ws/Cargo.toml",
+                    r#"[package]
+name = "ws"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+outside = { path = "../outside" }
+
+[workspace]
+"#
+                    .to_owned(),
+                ),
+                (
+                    "ws/src/main.rs",
+                    "fn main() {\n    outside::f();\n}\n".to_owned(),
+                ),
+                (
+                    "outside/Cargo.toml",
+                    "[package]\nname = \"outside\"\nversion = \"0.1.0\"\nedition = \"2018\"\n"
+                        .to_owned(),
+                ),
+                ("outside/src/lib.rs", "pub fn f() {}\n".to_owned()),
+            ],
+        );
+        let target_dir = dir.join("ws/target");
+        let reachable = build_and_collect(&dir.join("ws"), &target_dir);
+        let fingerprints = ondisk::list_names(&target_dir.join("debug/.fingerprint")).unwrap();
+        assert_collected(&reachable, &target_dir.join("debug"));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(
+            fingerprints.iter().any(|name| name.starts_with("outside-")),
+            "{:?}",
+            fingerprints,
+        );
+        assert_eq!(reachable.external.len(), 1, "{:?}", reachable.external);
+        assert!(reachable
+            .external
+            .iter()
+            .all(|id| id.starts_with("outside ")));
+    }
+
+    #[test]
+    fn wasm_companions() {
+        assert_eq!(
+            companions("wasm32-unknown-unknown", "app.wasm"),
+            ["app.wasm.map", "app.js"],
+        );
+        assert!(companions("x86_64-unknown-linux-gnu", "app.wasm").is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+//! Compaction of a profile directory by rebuilding it with hard links and swapping it in,
+//! so that concurrent builds never see a half-cleaned directory.
+use crate::sweep;
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Whether directories can be exchanged atomically, which compaction requires.
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// Rebuild `dir` in a sibling directory with hard links of all entries except `removed`,
+/// then swap them. Return the swapped out directory, which still contains everything,
+/// or `None` without touching anything if a build holds the lock of `dir`, since its
+/// writes would be lost in the swapped out directory.
+///
+/// Entries containing any `protected` paths are kept entirely.
+pub fn rebuild(
+    dir: &Path,
+    removed: &HashSet<PathBuf>,
+    protected: &HashSet<PathBuf>,
+) -> io::Result<Option<PathBuf>> {
+    // Cargo holds `.cargo-lock` of the profile directory during builds. The rebuilt directory
+    // links the same file, so builds starting after the swap wait for us too.
+    let lock_path = dir.join(".cargo-lock");
+    if !lock_path.exists() {
+        fs::File::create(&lock_path)?;
+    }
+    let _lock = match sweep::try_lock(&lock_path)? {
+        Some(lock) => lock,
+        None => return Ok(None),
+    };
+
+    let name = dir.file_name().expect("Profile directory has a name");
+    let new_dir = dir.with_file_name(format!(".{}.cargo-gc-new", name.to_string_lossy()));
+    let old_dir = dir.with_file_name(format!(".{}.cargo-gc-old", name.to_string_lossy()));
+    // Leftovers of an interrupted run.
+    for path in &[&new_dir, &old_dir] {
+        if path.exists() {
+            fs::remove_dir_all(path)?;
+        }
+    }
+
+    let keeps =
+        |path: &Path| !removed.contains(path) || protected.iter().any(|p| p.starts_with(path));
+    if let Err(err) = link_tree(dir, &new_dir, &keeps) {
+        let _ = fs::remove_dir_all(&new_dir);
+        return Err(err);
+    }
+    swap(dir, &new_dir)?;
+    fs::rename(&new_dir, &old_dir)?;
+    Ok(Some(old_dir))
+}
+
+/// Recreate the directory tree `src` at `dst`, with hard links of files for which `keeps`
+/// is true.
+fn link_tree(src: &Path, dst: &Path, keeps: &dyn Fn(&Path) -> bool) -> io::Result<()> {
+    fs::create_dir(dst)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let path = entry.path();
+        if !keeps(&path) {
+            continue;
+        }
+        let target = dst.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            link_tree(&path, &target, keeps)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(&path, &target)?;
+        } else {
+            fs::hard_link(&path, &target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(fs::read_link(src)?, dst)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(src: &Path, dst: &Path) -> io::Result<()> {
+    fs::copy(src, dst).map(|_| ())
+}
+
+/// Exchange `a` and `b` atomically.
+#[cfg(target_os = "linux")]
+fn swap(a: &Path, b: &Path) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn swap(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Directories cannot be exchanged atomically on this platform",
+    ))
+}
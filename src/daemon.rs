@@ -0,0 +1,92 @@
+//! Line-delimited JSON protocol of `cargo gc serve` over a Unix socket.
+//!
+//! Each request is a JSON object on its own line, answered by a JSON object on one line:
+//!
+//! - `{"method": "size"}`: `{"size": <bytes>}`, the current size of the target directory.
+//! - `{"method": "gc", "args": ["--dry-run"]}`: run GC with the options, and answer
+//!   `{"plan": ...}` in the schema of `--plan-file`.
+//! - `{"method": "plan"}`: `{"plan": ...}` of the last GC, or `null`.
+//!
+//! Failed requests are answered by `{"error": <message>}`.
+use anyhow::{bail, Context as _, Result};
+use serde_json::Value;
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum Request {
+    Size,
+    Gc { args: Vec<String> },
+    Plan,
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Result<Self> {
+        let req = serde_json::from_str::<Value>(line).context("Invalid JSON request")?;
+        match req.get("method").and_then(|v| v.as_str()) {
+            Some("size") => Ok(Self::Size),
+            Some("plan") => Ok(Self::Plan),
+            Some("gc") => {
+                let args = req.get("args").and_then(|v| v.as_array());
+                let args = args
+                    .into_iter()
+                    .flatten()
+                    .map(|arg| arg.as_str().map(|s| s.to_owned()))
+                    .collect::<Option<Vec<_>>>()
+                    .context("`args` must be an array of strings")?;
+                Ok(Self::Gc { args })
+            }
+            Some(method) => bail!("Unknown method `{}`", method),
+            None => bail!("Missing `method`"),
+        }
+    }
+}
+
+/// Listen on `socket`, and answer requests of each connection in turn by `handle`.
+/// A stale socket file left by a previous daemon is replaced.
+#[cfg(unix)]
+pub fn serve(socket: &Path, mut handle: impl FnMut(Request) -> Result<Value>) -> Result<()> {
+    use serde_json::json;
+    use std::{
+        fs,
+        io::{BufRead as _, BufReader, Write as _},
+        os::unix::{fs::FileTypeExt as _, net::UnixListener},
+    };
+
+    if let Ok(meta) = fs::symlink_metadata(socket) {
+        if !meta.file_type().is_socket() {
+            bail!("{} exists and is not a socket", socket.display());
+        }
+        fs::remove_file(socket)?;
+    }
+    let listener = UnixListener::bind(socket)
+        .with_context(|| format!("Failed to listen on {}", socket.display()))?;
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => {
+                    tracing::debug!("Connection closed: {}", err);
+                    break;
+                }
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let resp = match Request::parse(&line).and_then(&mut handle) {
+                Ok(resp) => resp,
+                Err(err) => json!({ "error": format!("{:#}", err) }),
+            };
+            if writeln!(writer, "{}", resp).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn serve(_socket: &Path, _handle: impl FnMut(Request) -> Result<Value>) -> Result<()> {
+    bail!("`cargo gc serve` is only supported on Unix")
+}
@@ -0,0 +1,138 @@
+//! Freshness of reachable units, evaluated by modification times as cargo does, to find
+//! artifacts which cargo rebuilds anyway.
+//! https://github.com/rust-lang/cargo/blob/0a4ec2917698ee067b257b580698d7ffb8ccbe2f/src/cargo/core/compiler/fingerprint.rs
+use crate::{collect::Reachable, explain};
+use serde_json::Value;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Indices of units in `reachable.graph` which cargo would rebuild, since their local files
+/// or those of their dependencies changed after their last builds. `dir` is the profile
+/// directory and `target_root` is the target directory its fingerprints are relative to.
+///
+/// Changes of environment variables are not considered, since builds may run in a different
+/// environment. Units whose freshness can't be determined are considered fresh.
+pub fn dirty_units(reachable: &Reachable, dir: &Path, target_root: &Path) -> Vec<usize> {
+    fn visit(
+        idx: usize,
+        reachable: &Reachable,
+        dir: &Path,
+        target_root: &Path,
+        memo: &mut Vec<Option<bool>>,
+    ) -> bool {
+        if let Some(dirty) = memo[idx] {
+            return dirty;
+        }
+        // Dependents of rebuilt units are rebuilt as well, since their outputs are newer.
+        memo[idx] = Some(false);
+        let node = &reachable.graph[idx];
+        let dirty = node
+            .deps
+            .iter()
+            .any(|&dep| visit(dep, reachable, dir, target_root, memo))
+            || {
+                let fingerprints = explain::read(&dir.join(".fingerprint").join(&node.pkg_dir))
+                    .unwrap_or_default();
+                explain::of_target(&fingerprints, &node.target_name).map_or(false, |(_, fp)| {
+                    is_locally_dirty(fp, &node.pkg_root, target_root)
+                })
+            };
+        memo[idx] = Some(dirty);
+        dirty
+    }
+
+    let mut memo = vec![None; reachable.graph.len()];
+    (0..reachable.graph.len())
+        .filter(|&idx| visit(idx, reachable, dir, target_root, &mut memo))
+        .collect()
+}
+
+/// Whether `local` of the fingerprint lists files modified after the build, or missing.
+/// Packages from registries or git are `Precalculated` and never dirty.
+fn is_locally_dirty(fingerprint: &Value, pkg_root: &Path, target_root: &Path) -> bool {
+    let locals = fingerprint.get("local").and_then(|local| local.as_array());
+    locals.into_iter().flatten().any(|local| {
+        if let Some(dep_info) = local
+            .pointer("/CheckDepInfo/dep_info")
+            .and_then(|path| path.as_str())
+        {
+            let dep_info = target_root.join(dep_info);
+            match fs::read(&dep_info).and_then(|bytes| parse_dep_info(&bytes)) {
+                Ok(files) => {
+                    let files = files.into_iter().map(|(is_target_relative, path)| {
+                        if is_target_relative {
+                            target_root.join(path)
+                        } else {
+                            pkg_root.join(path)
+                        }
+                    });
+                    has_newer(&dep_info, files)
+                }
+                Err(err) => err.kind() == io::ErrorKind::NotFound,
+            }
+        } else if let Some(output) = local
+            .pointer("/RerunIfChanged/output")
+            .and_then(|path| path.as_str())
+        {
+            let paths = local.pointer("/RerunIfChanged/paths");
+            let paths = paths.and_then(|paths| paths.as_array());
+            let paths = paths
+                .into_iter()
+                .flatten()
+                .filter_map(|path| Some(pkg_root.join(path.as_str()?)));
+            has_newer(&target_root.join(output), paths)
+        } else {
+            false
+        }
+    })
+}
+
+/// Whether any of `paths` is modified after `reference`, or is missing.
+fn has_newer(reference: &Path, mut paths: impl Iterator<Item = PathBuf>) -> bool {
+    let mtime = |path: &Path| fs::metadata(path).and_then(|meta| meta.modified());
+    let reference = match mtime(reference) {
+        Ok(mtime) => mtime,
+        Err(err) => return err.kind() == io::ErrorKind::NotFound,
+    };
+    paths.any(|path| match mtime(&path) {
+        Ok(mtime) => mtime > reference,
+        Err(err) => err.kind() == io::ErrorKind::NotFound,
+    })
+}
+
+/// Parse files of an encoded dep-info file in the fingerprint directory, with whether each
+/// is relative to the target root instead of the package root.
+fn parse_dep_info(mut bytes: &[u8]) -> io::Result<Vec<(bool, PathBuf)>> {
+    fn invalid() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, "invalid dep-info")
+    }
+    fn take<'a>(bytes: &mut &'a [u8], len: usize) -> io::Result<&'a [u8]> {
+        if bytes.len() < len {
+            return Err(invalid());
+        }
+        let (head, rest) = bytes.split_at(len);
+        *bytes = rest;
+        Ok(head)
+    }
+    fn take_u32(bytes: &mut &[u8]) -> io::Result<usize> {
+        let mut buf = [0; 4];
+        buf.copy_from_slice(take(bytes, 4)?);
+        Ok(u32::from_le_bytes(buf) as usize)
+    }
+
+    let count = take_u32(&mut bytes)?;
+    let mut files = Vec::with_capacity(count.min(bytes.len()));
+    for _ in 0..count {
+        let is_target_relative = match take(&mut bytes, 1)?[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(invalid()),
+        };
+        let len = take_u32(&mut bytes)?;
+        let path = std::str::from_utf8(take(&mut bytes, len)?).map_err(|_| invalid())?;
+        files.push((is_target_relative, PathBuf::from(path)));
+    }
+    Ok(files)
+}
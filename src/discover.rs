@@ -0,0 +1,69 @@
+//! Discovery of other workspaces sharing the same target directory.
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// File in the target directory listing root manifests of workspaces using it, one per line.
+const OWNERS_FILE: &str = ".cargo-gc-owners";
+
+/// Read registered owner manifests of the target directory.
+pub fn read_owners(target_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    match fs::read_to_string(target_dir.join(OWNERS_FILE)) {
+        Ok(content) => Ok(content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(PathBuf::from)
+            .collect()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Overwrite registered owner manifests of the target directory.
+pub fn write_owners(target_dir: &Path, owners: &[PathBuf]) -> io::Result<()> {
+    let content = owners
+        .iter()
+        .map(|p| format!("{}\n", p.display()))
+        .collect::<String>();
+    fs::write(target_dir.join(OWNERS_FILE), content)
+}
+
+/// The root of the repository containing `path`, or `path` itself if not in a repository.
+pub fn repo_root(path: &Path) -> &Path {
+    path.ancestors()
+        .find(|dir| dir.join(".git").exists())
+        .unwrap_or(path)
+}
+
+/// Find all `Cargo.toml` under `root` recursively, skipping hidden directories
+/// and target directories.
+pub fn find_manifests(root: &Path, target_dir: &Path) -> Vec<PathBuf> {
+    let mut ret = Vec::new();
+    let mut stack = vec![root.to_owned()];
+    while let Some(dir) = stack.pop() {
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(err) => {
+                tracing::debug!("Cannot read {}: {}", dir.display(), err);
+                continue;
+            }
+        };
+        for entry in entries.filter_map(|entry| entry.ok()) {
+            let path = entry.path();
+            let file_name = entry.file_name();
+            let name = file_name.to_string_lossy();
+            match entry.file_type() {
+                Ok(ty) if ty.is_dir() => {
+                    if !name.starts_with('.') && name != "target" && path != target_dir {
+                        stack.push(path);
+                    }
+                }
+                Ok(ty) if ty.is_file() && name == "Cargo.toml" => ret.push(path),
+                _ => {}
+            }
+        }
+    }
+    ret.sort();
+    ret
+}
@@ -0,0 +1,195 @@
+//! Diagnose configurations making the target directory large and churny.
+use crate::{branches, discover, dupes::Duplicates, size::dir_size};
+use bytesize::ByteSize;
+use cargo::{core::Workspace, util::toml::U32OrBool, CargoResult};
+use std::{collections::HashSet, fmt, fs, io, path::Path};
+
+/// Report duplicates when at least this many packages have multiple versions.
+const DUPLICATES_THRESHOLD: usize = 5;
+
+/// A problem found and a suggested change of configuration.
+#[derive(Debug)]
+pub struct Finding {
+    pub problem: String,
+    pub suggestion: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "- {}", self.problem)?;
+        for line in self.suggestion.lines() {
+            writeln!(f, "  {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check incremental compilation and debuginfo of the profile directory `dir`.
+pub fn check_profile(
+    ws: &Workspace,
+    profile: &str,
+    display_profile: &str,
+    dir: &Path,
+) -> CargoResult<Vec<Finding>> {
+    let mut ret = Vec::new();
+
+    let incremental_dir = dir.join("incremental");
+    if profile == "release" && incremental_dir.is_dir() {
+        ret.push(Finding {
+            problem: format!(
+                "Incremental compilation is used in `release`, taking {}",
+                ByteSize(dir_size(&incremental_dir)?).to_string_as(true),
+            ),
+            suggestion: "\
+It rarely speeds up optimized builds. Disable it in `Cargo.toml`:
+    [profile.release]
+    incremental = false"
+                .to_owned(),
+        });
+    }
+
+    // `.cargo/config` overrides `Cargo.toml`.
+    let key = format!("profile.{}.debug", profile);
+    let debug = match ws.config().get::<Option<U32OrBool>>(&key)? {
+        Some(debug) => Some(debug),
+        None => ws
+            .profiles()
+            .and_then(|profiles| profiles.get(profile))
+            .and_then(|profile| profile.debug.clone()),
+    };
+    let full_debuginfo = match debug {
+        Some(U32OrBool::U32(level)) => level >= 2,
+        Some(U32OrBool::Bool(debug)) => debug,
+        None => profile == "dev",
+    };
+    let deps_dir = dir.join("deps");
+    if full_debuginfo && deps_dir.is_dir() {
+        ret.push(Finding {
+            problem: format!(
+                "Full debuginfo is enabled in `{}`, whose dependencies take {}",
+                display_profile,
+                ByteSize(dir_size(&deps_dir)?).to_string_as(true),
+            ),
+            suggestion: format!(
+                "\
+Debuginfo of dependencies is rarely used. Keep only line tables, or drop it for
+dependencies in `Cargo.toml`:
+    [profile.{}]
+    debug = 1
+    [profile.{}.package.\"*\"]
+    debug = false",
+                profile, profile,
+            ),
+        });
+    }
+
+    Ok(ret)
+}
+
+/// Check packages with artifacts of multiple versions in a profile directory.
+pub fn check_duplicates(display_profile: &str, duplicates: &Duplicates) -> Option<Finding> {
+    if duplicates.len() < DUPLICATES_THRESHOLD {
+        return None;
+    }
+    let size = duplicates
+        .values()
+        .flat_map(|versions| versions.values())
+        .map(|artifacts| artifacts.size)
+        .sum::<u64>();
+    Some(Finding {
+        problem: format!(
+            "{} packages have artifacts of multiple versions in `{}`, taking {}",
+            duplicates.len(),
+            display_profile,
+            ByteSize(size).to_string_as(true),
+        ),
+        suggestion: "\
+Run `cargo gc duplicates --dedupe-suggestions` to see dependents to upgrade, then
+`cargo update` to unify versions."
+            .to_owned(),
+    })
+}
+
+/// Check the number of distinct rustc versions recorded in fingerprints of a profile
+/// directory.
+pub fn check_rustc_versions(display_profile: &str, dir: &Path) -> io::Result<Option<Finding>> {
+    let versions = rustc_versions(&dir.join(".fingerprint"))?;
+    if versions.len() <= 1 {
+        return Ok(None);
+    }
+    Ok(Some(Finding {
+        problem: format!(
+            "Artifacts in `{}` are built by {} distinct rustc versions",
+            display_profile,
+            versions.len(),
+        ),
+        suggestion: "\
+Artifacts of other toolchains are never reused by the current one. Pin the
+toolchain in `rust-toolchain.toml`, and run `cargo gc` after upgrading it, or
+`cargo gc --toolchain <TOOLCHAIN>` to keep ones still in use."
+            .to_owned(),
+    }))
+}
+
+/// Hashes of rustc versions in fingerprint files, which are recorded by cargo as `rustc`.
+fn rustc_versions(fingerprint_dir: &Path) -> io::Result<HashSet<u64>> {
+    let mut ret = HashSet::new();
+    if !fingerprint_dir.is_dir() {
+        return Ok(ret);
+    }
+    for entry in fs::read_dir(fingerprint_dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&path)? {
+            let path = entry?.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            let rustc = fs::read_to_string(&path)
+                .ok()
+                .and_then(|s| serde_json::from_str::<serde_json::Value>(&s).ok())
+                .and_then(|v| v.get("rustc")?.as_u64());
+            ret.extend(rustc);
+        }
+    }
+    Ok(ret)
+}
+
+/// Check other git worktrees of the repository containing `ws_root`, which have their own
+/// target directories if `target_dir` is inside the repository.
+pub fn check_worktrees(ws_root: &Path, target_dir: &Path) -> Option<Finding> {
+    let repo_root = discover::repo_root(ws_root);
+    if !target_dir.starts_with(repo_root) {
+        return None;
+    }
+    let ws_relative = ws_root.strip_prefix(repo_root).ok()?;
+    let list = branches::git(ws_root, &["worktree", "list", "--porcelain"])?;
+    let unshared = list
+        .lines()
+        .filter_map(|line| line.strip_prefix("worktree "))
+        .map(Path::new)
+        .filter(|worktree| *worktree != repo_root)
+        .map(|worktree| worktree.join(ws_relative).join("target"))
+        .filter(|dir| dir.is_dir())
+        .collect::<Vec<_>>();
+    if unshared.is_empty() {
+        return None;
+    }
+    Some(Finding {
+        problem: format!(
+            "{} other git worktrees have their own target directories:{}",
+            unshared.len(),
+            unshared
+                .iter()
+                .map(|p| format!("\n    {}", p.display()))
+                .collect::<String>(),
+        ),
+        suggestion: "\
+Dependencies are built once per worktree. Share a target directory by setting
+`CARGO_TARGET_DIR`, and run `cargo gc --register` in each worktree so that their
+artifacts are kept."
+            .to_owned(),
+    })
+}
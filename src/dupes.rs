@@ -0,0 +1,158 @@
+//! Find packages with artifacts of multiple versions in a profile directory.
+use crate::{collect::Reachable, ondisk};
+use bytesize::ByteSize;
+use semver::Version;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fs, io,
+    path::Path,
+};
+
+/// Artifacts of a version of a package.
+#[derive(Debug, Default)]
+pub struct Artifacts {
+    pub size: u64,
+    pub reachable: bool,
+    /// Package ids of reachable units depending on it.
+    pub dependents: BTreeSet<String>,
+}
+
+/// Package name -> version -> artifacts, for packages with multiple versions.
+pub type Duplicates = BTreeMap<String, BTreeMap<String, Artifacts>>;
+
+pub fn find(dir: &Path, reachable: &Reachable) -> io::Result<Duplicates> {
+    // Fingerprint directory -> (version, dependents)
+    let mut known = HashMap::<&str, (&str, BTreeSet<String>)>::new();
+    for node in &reachable.graph {
+        // `{name} v{version}` with an optional source.
+        let version = node.package_id.split(' ').nth(1).unwrap_or_default();
+        let version = version.strip_prefix('v').unwrap_or(version);
+        known
+            .entry(node.pkg_dir.as_str())
+            .or_insert((version, BTreeSet::new()));
+    }
+    for node in &reachable.graph {
+        for &dep in &node.deps {
+            if let Some((_, dependents)) = known.get_mut(reachable.graph[dep].pkg_dir.as_str()) {
+                dependents.insert(node.package_id.clone());
+            }
+        }
+    }
+
+    let deps = ondisk::list_names(&dir.join("deps"))?;
+    let mut ret = Duplicates::new();
+    for pkg_dir in ondisk::list_names(&dir.join(".fingerprint"))? {
+        let (name, hash) = match ondisk::split_hash(&pkg_dir) {
+            Some(x) => x,
+            None => continue,
+        };
+        let files = deps
+            .iter()
+            .filter(|file| {
+                ondisk::split_hash(file.split('.').next().unwrap()).map(|(_, h)| h) == Some(hash)
+            })
+            .collect::<Vec<_>>();
+        let (version, dependents) = match known.get(pkg_dir.as_str()) {
+            Some((version, dependents)) => (version.to_string(), Some(dependents)),
+            None => {
+                let version = files
+                    .iter()
+                    .filter(|file| file.ends_with(".d"))
+                    .find_map(|file| version_from_dep_info(&dir.join("deps").join(file), name));
+                match version {
+                    Some(version) => (version, None),
+                    None => continue,
+                }
+            }
+        };
+
+        let mut size = crate::size::dir_size(&dir.join(".fingerprint").join(&pkg_dir))?;
+        let build_dir = dir.join("build").join(&pkg_dir);
+        if build_dir.exists() {
+            size += crate::size::dir_size(&build_dir)?;
+        }
+        for file in files {
+            size += crate::size::dir_size(&dir.join("deps").join(file))?;
+        }
+
+        let artifacts = ret
+            .entry(name.to_owned())
+            .or_default()
+            .entry(version)
+            .or_default();
+        artifacts.size += size;
+        if let Some(dependents) = dependents {
+            artifacts.reachable = true;
+            artifacts.dependents.extend(dependents.iter().cloned());
+        }
+    }
+    ret.retain(|_, versions| versions.len() > 1);
+    Ok(ret)
+}
+
+/// Guess the version of package `name` from source paths in a dep-info file,
+/// which are like `~/.cargo/registry/src/<index>/<name>-<version>/src/lib.rs`.
+fn version_from_dep_info(path: &Path, name: &str) -> Option<String> {
+    let content = fs::read_to_string(path).ok()?;
+    let prefix = format!("{}-", name);
+    content
+        .split_whitespace()
+        .flat_map(|path| Path::new(path).components())
+        .filter_map(|c| {
+            c.as_os_str()
+                .to_str()?
+                .strip_prefix(&prefix)
+                .map(str::to_owned)
+        })
+        .find(|version| Version::parse(version).is_ok())
+}
+
+pub fn report(duplicates: &Duplicates, suggestions: bool) -> String {
+    let mut out = String::new();
+    for (name, versions) in duplicates {
+        out += &format!("{}: {} versions\n", name, versions.len());
+        for (version, artifacts) in sorted(versions) {
+            out += &format!(
+                "  {:<12} {:>10}  {}\n",
+                version,
+                ByteSize(artifacts.size).to_string_as(true),
+                if artifacts.reachable {
+                    "reachable"
+                } else {
+                    "unreachable"
+                },
+            );
+        }
+        if !suggestions {
+            continue;
+        }
+        let reachable = sorted(versions)
+            .into_iter()
+            .filter(|(_, artifacts)| artifacts.reachable)
+            .collect::<Vec<_>>();
+        if let Some(((latest, _), older)) = reachable.split_last() {
+            for (version, artifacts) in older {
+                out += &format!(
+                    "  help: upgrade dependents of {} {} to unify with {}: {}\n",
+                    name,
+                    version,
+                    latest,
+                    artifacts
+                        .dependents
+                        .iter()
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                );
+            }
+        }
+    }
+    out
+}
+
+/// Versions in ascending order.
+fn sorted(versions: &BTreeMap<String, Artifacts>) -> Vec<(&String, &Artifacts)> {
+    let mut ret = versions.iter().collect::<Vec<_>>();
+    ret.sort_by_key(|(version, _)| Version::parse(version).ok());
+    ret
+}
@@ -0,0 +1,151 @@
+//! Explanation of differences between fingerprints recorded by cargo, for stale artifacts and
+//! spurious rebuilds.
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+/// Fields of fingerprint JSON files, with what they capture.
+/// https://github.com/rust-lang/cargo/blob/0a4ec2917698ee067b257b580698d7ffb8ccbe2f/src/cargo/core/compiler/fingerprint.rs
+const FIELDS: &[(&str, &str)] = &[
+    ("rustc", "rustc version or workspace wrapper"),
+    ("features", "features"),
+    ("target", "target"),
+    ("profile", "profile settings"),
+    ("path", "source path"),
+    ("deps", "dependencies"),
+    ("local", "local sources or build script outputs"),
+    ("rustflags", "RUSTFLAGS"),
+    ("metadata", "package metadata"),
+    ("config", "unstable flags"),
+    ("compile_kind", "target triple"),
+];
+
+/// Fingerprint JSON files in the fingerprint directory `dir`, by file name.
+pub fn read(dir: &Path) -> io::Result<BTreeMap<String, Value>> {
+    let mut ret = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if name.ends_with(".json") => name.to_owned(),
+            _ => continue,
+        };
+        if let Ok(value) = serde_json::from_str(&fs::read_to_string(&path)?) {
+            ret.insert(name, value);
+        }
+    }
+    Ok(ret)
+}
+
+/// The fingerprint of target `target_name` in `fingerprints`, whose files are named
+/// `{mode}{kind}-{target}.json`.
+pub fn of_target<'a>(
+    fingerprints: &'a BTreeMap<String, Value>,
+    target_name: &str,
+) -> Option<(&'a str, &'a Value)> {
+    let suffix = format!("-{}.json", target_name);
+    fingerprints
+        .iter()
+        .find(|(name, _)| name.ends_with(&suffix))
+        .map(|(name, value)| (&**name, value))
+}
+
+/// Fields of a fingerprint computable without building, in the format of cargo.
+pub fn computed(features: &[String], rustc: u64, rustflags: &[String]) -> Value {
+    json!({
+        "rustc": rustc,
+        "features": format!("{:?}", features),
+        "rustflags": rustflags,
+    })
+}
+
+/// The fingerprint hash of target `target_name` in the fingerprint directory `dir`, written
+/// in hex besides its JSON file.
+pub fn read_hash(dir: &Path, target_name: &str) -> Option<u64> {
+    let suffix = format!("-{}", target_name);
+    let name = crate::ondisk::list_names(dir)
+        .ok()?
+        .into_iter()
+        .find(|name| name.ends_with(&suffix))?;
+    u64::from_str_radix(fs::read_to_string(dir.join(name)).ok()?.trim(), 16).ok()
+}
+
+/// Fingerprint hashes of dependencies recorded in `fingerprint`.
+pub fn dep_hashes(fingerprint: &Value) -> Vec<u64> {
+    let deps = fingerprint.get("deps").and_then(|deps| deps.as_array());
+    deps.into_iter()
+        .flatten()
+        .filter_map(|dep| dep.get(3)?.as_u64())
+        .collect()
+}
+
+/// Describe how `new` differs from `old`, for each field present in `new`.
+pub fn diff(old: &Value, new: &Value) -> Vec<String> {
+    compare(old, new)
+        .into_iter()
+        .filter_map(|(field, detail)| Some(format!("{}: {}", field, detail?)))
+        .collect()
+}
+
+/// Compare each field present in `new` with `old`, with descriptions of differences.
+pub fn compare(old: &Value, new: &Value) -> Vec<(String, Option<String>)> {
+    let mut ret = Vec::new();
+    for &(field, what) in FIELDS {
+        let label = format!("{} (`{}`)", what, field);
+        let (old, new) = match (old.get(field), new.get(field)) {
+            (_, None) => continue,
+            (old, Some(new)) if old == Some(new) => {
+                ret.push((label, None));
+                continue;
+            }
+            (old, Some(new)) => (old, new),
+        };
+        let detail = match (old, new) {
+            (Some(Value::Array(old)), Value::Array(new)) if field == "deps" => diff_deps(old, new),
+            // Hashes of inputs.
+            (Some(Value::Number(_)), Value::Number(_)) => "changed".to_owned(),
+            (old, new) => format!(
+                "{} -> {}",
+                old.map_or_else(|| "none".to_owned(), |v| v.to_string()),
+                new,
+            ),
+        };
+        ret.push((label, Some(detail)));
+    }
+    ret
+}
+
+/// Dependencies are `[package hash, extern name, public, fingerprint hash]`.
+fn diff_deps(old: &[Value], new: &[Value]) -> String {
+    let by_name = |deps: &[Value]| {
+        deps.iter()
+            .filter_map(|dep| Some((dep.get(1)?.as_str()?.to_owned(), dep.clone())))
+            .collect::<BTreeMap<_, _>>()
+    };
+    let (old, new) = (by_name(old), by_name(new));
+    let mut parts = Vec::new();
+    let mut push = |label: &str, names: Vec<&String>| {
+        if !names.is_empty() {
+            let names = names.iter().map(|s| s.as_str()).collect::<Vec<_>>();
+            parts.push(format!("{} {}", label, names.join(", ")));
+        }
+    };
+    push(
+        "rebuilt",
+        new.iter()
+            .filter(|(name, dep)| old.get(*name).map_or(false, |old| old != *dep))
+            .map(|(name, _)| name)
+            .collect(),
+    );
+    push(
+        "added",
+        new.keys().filter(|name| !old.contains_key(*name)).collect(),
+    );
+    push(
+        "removed",
+        old.keys().filter(|name| !new.contains_key(*name)).collect(),
+    );
+    if parts.is_empty() {
+        "changed".to_owned()
+    } else {
+        parts.join("; ")
+    }
+}
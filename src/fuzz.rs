@@ -0,0 +1,60 @@
+//! Collection of target directories of `cargo fuzz`.
+use serde_json::Value;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// The manifest of the fuzz crate created by `cargo fuzz init` in the workspace `ws_root`.
+pub fn manifest(ws_root: &Path) -> Option<PathBuf> {
+    let manifest = ws_root.join("fuzz").join("Cargo.toml");
+    if manifest.is_file() {
+        Some(manifest)
+    } else {
+        None
+    }
+}
+
+/// RUSTFLAGS of the latest build in the profile directory `dir`, recorded in fingerprints.
+///
+/// `cargo fuzz` passes sanitizer and coverage instrumentation flags by RUSTFLAGS, which are
+/// hashed into metadata. They vary between its versions and options, so they are read back
+/// instead of being reproduced. Build scripts get no RUSTFLAGS when cross compiling and are
+/// ignored.
+pub fn rustflags(dir: &Path) -> io::Result<Option<String>> {
+    let fingerprint_dir = dir.join(".fingerprint");
+    let mut latest = None::<(SystemTime, Vec<String>)>;
+    for entry in fs::read_dir(&fingerprint_dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&path)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().map_or(true, |ext| ext != "json") {
+                continue;
+            }
+            let mtime = entry.metadata()?.modified()?;
+            if latest.as_ref().map_or(false, |(t, _)| *t >= mtime) {
+                continue;
+            }
+            let flags = fs::read_to_string(&path)
+                .ok()
+                .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+                .and_then(|v| {
+                    v.get("rustflags")?
+                        .as_array()?
+                        .iter()
+                        .map(|flag| Some(flag.as_str()?.to_owned()))
+                        .collect::<Option<Vec<_>>>()
+                });
+            match flags {
+                Some(flags) if !flags.is_empty() => latest = Some((mtime, flags)),
+                _ => {}
+            }
+        }
+    }
+    Ok(latest.map(|(_, flags)| flags.join(" ")))
+}
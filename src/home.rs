@@ -0,0 +1,271 @@
+//! GC of the cargo home, keeping entries referenced by lock files of known projects.
+use crate::{discover, remove::Remover, size::dir_size, sweep};
+use anyhow::{Context as _, Result};
+use std::{
+    collections::HashSet,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
+};
+
+/// Packages referenced by known lock files.
+#[derive(Debug, Default)]
+pub struct Referenced {
+    /// `{name}-{version}` of registry packages.
+    pub registry: HashSet<String>,
+    /// Commit hashes of git packages.
+    pub git_revs: HashSet<String>,
+}
+
+/// Lock files of `projects`, or all lock files under them if `recursive` is set.
+pub fn find_lockfiles(projects: &[PathBuf], recursive: bool) -> Vec<PathBuf> {
+    let mut ret = Vec::new();
+    for project in projects {
+        if recursive {
+            let manifests = discover::find_manifests(project, &project.join("target"));
+            ret.extend(
+                manifests
+                    .iter()
+                    .map(|manifest| manifest.with_file_name("Cargo.lock"))
+                    .filter(|lockfile| lockfile.is_file()),
+            );
+        } else {
+            let lockfile = project.join("Cargo.lock");
+            if lockfile.is_file() {
+                ret.push(lockfile);
+            }
+        }
+    }
+    ret.sort();
+    ret.dedup();
+    ret
+}
+
+/// Collect packages referenced by `lockfiles`.
+pub fn referenced(lockfiles: &[PathBuf]) -> Result<Referenced> {
+    let mut ret = Referenced::default();
+    for lockfile in lockfiles {
+        let content = fs::read_to_string(lockfile)?;
+        let lock = content
+            .parse::<toml::Value>()
+            .with_context(|| format!("Invalid lock file {}", lockfile.display()))?;
+        let packages = lock.get("package").and_then(|v| v.as_array());
+        for pkg in packages.into_iter().flatten() {
+            let field = |key: &str| pkg.get(key).and_then(|v| v.as_str());
+            let (name, version, source) = match (field("name"), field("version"), field("source")) {
+                (Some(name), Some(version), Some(source)) => (name, version, source),
+                // Path dependencies have no source.
+                _ => continue,
+            };
+            if let Some(url) = source.strip_prefix("git+") {
+                // Locked git sources are `{url}#{commit}`.
+                if let Some(idx) = url.rfind('#') {
+                    ret.git_revs.insert(url[idx + 1..].to_owned());
+                }
+            } else {
+                // Registries, including `sparse+` ones, are all cached as `{name}-{version}`.
+                // Unknown source kinds are kept likewise, in case they are cached the same way.
+                if !source.starts_with("registry+") && !source.starts_with("sparse+") {
+                    tracing::debug!("Keeping {} {} of unknown source {}", name, version, source);
+                }
+                ret.registry.insert(format!("{}-{}", name, version));
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Categories of the cargo home, by their paths relative to it.
+const CATEGORIES: &[&str] = &["registry/cache", "registry/src", "git/checkouts", "git/db"];
+
+/// Sizes of each category in the cargo home `home`.
+pub fn stats(home: &Path) -> io::Result<Vec<(&'static str, u64)>> {
+    let mut ret = Vec::new();
+    for &category in CATEGORIES {
+        let path = home.join(category);
+        let size = if path.exists() { dir_size(&path)? } else { 0 };
+        ret.push((category, size));
+    }
+    Ok(ret)
+}
+
+/// What to keep in the cargo home.
+#[derive(Debug)]
+pub struct Plan {
+    pub home: PathBuf,
+    pub referenced: Referenced,
+    /// Keep unreferenced entries modified within this period.
+    pub max_age: Option<Duration>,
+}
+
+impl Plan {
+    /// Remove `.crate` files in `registry/cache` not referenced.
+    /// Cargo downloads them again on demand.
+    pub fn gc_registry_cache(&self, remover: &mut Remover) -> Result<()> {
+        let cache_dir = self.home.join("registry").join("cache");
+        if !cache_dir.is_dir() {
+            return Ok(());
+        }
+        // One directory for each registry index.
+        for index in remover.read_dir(&cache_dir)? {
+            for entry in remover.read_dir(&index.path())? {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                let pkg = match name.strip_suffix(".crate") {
+                    Some(pkg) => pkg,
+                    None => continue,
+                };
+                if !self.referenced.registry.contains(pkg) && self.is_expired(&entry.path()) {
+                    remover.remove(&entry.path(), "crate not referenced by any known lock file")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove extracted sources in `registry/src` which are not referenced, or whose
+    /// `.crate` file is gone. Cargo extracts them again from the cache.
+    pub fn gc_registry_src(&self, remover: &mut Remover) -> Result<()> {
+        let src_dir = self.home.join("registry").join("src");
+        if !src_dir.is_dir() {
+            return Ok(());
+        }
+        let cache_dir = self.home.join("registry").join("cache");
+        for index in remover.read_dir(&src_dir)? {
+            let index_cache_dir = cache_dir.join(index.file_name());
+            for entry in remover.read_dir(&index.path())? {
+                let pkg = entry.file_name().to_string_lossy().into_owned();
+                let crate_file = index_cache_dir.join(format!("{}.crate", pkg));
+                if !self.referenced.registry.contains(&pkg) {
+                    if self.is_expired(&entry.path()) {
+                        remover.remove(
+                            &entry.path(),
+                            "source not referenced by any known lock file",
+                        )?;
+                    }
+                } else if !crate_file.is_file() || remover.is_removed(&crate_file) {
+                    remover.remove(&entry.path(), "source whose crate file is gone")?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove checkouts in `git/checkouts` at revisions not referenced.
+    /// Checkouts are named by abbreviated commit hashes.
+    pub fn gc_git_checkouts(&self, remover: &mut Remover) -> Result<()> {
+        let checkouts_dir = self.home.join("git").join("checkouts");
+        if !checkouts_dir.is_dir() {
+            return Ok(());
+        }
+        // One directory for each repository.
+        for repo in remover.read_dir(&checkouts_dir)? {
+            for entry in remover.read_dir(&repo.path())? {
+                let short_rev = entry.file_name().to_string_lossy().into_owned();
+                let is_referenced = self
+                    .referenced
+                    .git_revs
+                    .iter()
+                    .any(|rev| rev.starts_with(&short_rev));
+                if !is_referenced && self.is_expired(&entry.path()) {
+                    remover.remove(
+                        &entry.path(),
+                        "checkout not referenced by any known lock file",
+                    )?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove bare repositories in `git/db` without any remaining checkout, which share the
+    /// same directory name, and prune the others by `git gc`.
+    pub fn gc_git_db(&self, remover: &mut Remover) -> Result<()> {
+        let db_dir = self.home.join("git").join("db");
+        if !db_dir.is_dir() {
+            return Ok(());
+        }
+        let checkouts_dir = self.home.join("git").join("checkouts");
+        for entry in remover.read_dir(&db_dir)? {
+            let repo_checkouts = checkouts_dir.join(entry.file_name());
+            let has_checkout = repo_checkouts.is_dir()
+                && remover
+                    .read_dir(&repo_checkouts)?
+                    .iter()
+                    .any(|checkout| !remover.is_removed(&checkout.path()));
+            if !has_checkout {
+                if self.is_expired(&entry.path()) {
+                    remover.remove(&entry.path(), "repository without any kept checkout")?;
+                }
+            } else if !remover.dry_run {
+                let status = Command::new("git")
+                    .arg("-C")
+                    .arg(entry.path())
+                    .args(&["gc", "--quiet", "--prune=now"])
+                    .status();
+                if !status.map_or(false, |status| status.success()) {
+                    tracing::debug!("Failed to run `git gc` in {}", entry.path().display());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_expired(&self, path: &Path) -> bool {
+        sweep::is_expired(path, self.max_age)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn referenced_sources() {
+        let lockfile = env::temp_dir().join(format!("cargo-gc-lock-{}", std::process::id()));
+        fs::write(
+            &lockfile,
+            r#"version = 3
+
+[[package]]
+name = "local"
+version = "0.1.0"
+
+[[package]]
+name = "crates-io"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "sparse"
+version = "2.0.0"
+source = "sparse+https://index.crates.io/"
+
+[[package]]
+name = "future"
+version = "3.0.0"
+source = "future+https://example.com/"
+
+[[package]]
+name = "git"
+version = "0.1.0"
+source = "git+https://example.com/repo#0123456789abcdef0123456789abcdef01234567"
+"#,
+        )
+        .unwrap();
+        let referenced = referenced(&[lockfile.clone()]).unwrap();
+        fs::remove_file(&lockfile).unwrap();
+
+        let mut registry = referenced.registry.iter().cloned().collect::<Vec<_>>();
+        registry.sort();
+        assert_eq!(
+            registry,
+            ["crates-io-1.0.0", "future-3.0.0", "sparse-2.0.0"]
+        );
+        assert!(referenced
+            .git_revs
+            .contains("0123456789abcdef0123456789abcdef01234567"));
+    }
+}
@@ -0,0 +1,57 @@
+//! Opt-in index of all workspaces of the user, whose lock files are known to the cargo home
+//! GC.
+use anyhow::{Context as _, Result};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// `$XDG_CONFIG_HOME/cargo-gc/workspaces.toml`, or under `%APPDATA%` on Windows.
+pub fn path() -> Option<PathBuf> {
+    let config_dir = if cfg!(windows) {
+        PathBuf::from(env::var_os("APPDATA")?)
+    } else {
+        match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        }
+    };
+    Some(config_dir.join("cargo-gc").join("workspaces.toml"))
+}
+
+/// Registered workspace directories. Empty if the index does not exist.
+pub fn load(path: &Path) -> Result<Vec<PathBuf>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let index = content
+        .parse::<toml::Value>()
+        .with_context(|| format!("Invalid workspace index {}", path.display()))?;
+    let workspaces = index.get("workspaces").and_then(|v| v.as_array());
+    Ok(workspaces
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v.as_str())
+        .map(PathBuf::from)
+        .collect())
+}
+
+pub fn save(path: &Path, workspaces: &[PathBuf]) -> Result<()> {
+    let mut index = toml::value::Table::new();
+    index.insert(
+        "workspaces".into(),
+        toml::Value::Array(
+            workspaces
+                .iter()
+                .map(|p| toml::Value::String(p.to_string_lossy().into_owned()))
+                .collect(),
+        ),
+    );
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, toml::to_string(&toml::Value::Table(index))?)
+        .with_context(|| format!("Failed to write workspace index {}", path.display()))
+}
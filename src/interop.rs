@@ -0,0 +1,232 @@
+//! Stable JSON description of caches and the removal plan, for other tools like cargo-cache
+//! or IDE plugins to consume, and keep-lists supplied by them in the same schema.
+//!
+//! ```json
+//! {
+//!   "schema": "cargo-gc-plan",
+//!   "version": 1,
+//!   "dry_run": true,
+//!   "caches": [{ "kind": "target-dir", "path": "/repo/target", "size": 1024 }],
+//!   "plan": [{ "path": "/repo/target/debug/deps/libfoo-0123456789abcdef.rlib",
+//!              "category": "deps", "reason": "unreachable" }],
+//!   "keep": ["/repo/target/debug/deps/libbar-0123456789abcdef.rlib"]
+//! }
+//! ```
+//!
+//! Fields may be added in the same version. Consumers should ignore unknown fields.
+use crate::metrics;
+use anyhow::{ensure, Context as _, Result};
+use serde_json::{json, Value};
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+use structopt::clap::arg_enum;
+
+arg_enum! {
+    /// Shells of scripts written by `Report::write_script`.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ScriptFormat {
+        Sh,
+        PowerShell,
+    }
+}
+
+const SCHEMA: &str = "cargo-gc-plan";
+const VERSION: u64 = 1;
+
+/// A cache directory, like a target directory.
+#[derive(Debug)]
+pub struct Cache {
+    pub kind: &'static str,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub dry_run: bool,
+    pub caches: Vec<Cache>,
+    /// Removed, or to be removed in dry-run, paths with reasons.
+    pub plan: Vec<(PathBuf, String)>,
+    /// Paths protected from removal.
+    pub keep: Vec<PathBuf>,
+}
+
+impl Report {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "schema": SCHEMA,
+            "version": VERSION,
+            "dry_run": self.dry_run,
+            "caches": self.caches.iter().map(|cache| json!({
+                "kind": cache.kind,
+                "path": cache.path.display().to_string(),
+                "size": cache.size,
+            })).collect::<Vec<_>>(),
+            "plan": self.plan.iter().map(|(path, reason)| json!({
+                "path": path.display().to_string(),
+                "category": metrics::category(path),
+                "reason": reason,
+            })).collect::<Vec<_>>(),
+            "keep": self.keep.iter().map(|p| p.display().to_string()).collect::<Vec<_>>(),
+        })
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let content = serde_json::to_string_pretty(&self.to_json())?;
+        fs::write(path, content)
+            .with_context(|| format!("Failed to write plan to {}", path.display()))
+    }
+
+    /// Write a script removing paths of the plan, to be reviewed or run where cargo-gc can't.
+    pub fn write_script(&self, path: &Path, format: ScriptFormat) -> Result<()> {
+        let mut content = String::new();
+        match format {
+            ScriptFormat::Sh => content.push_str("#!/bin/sh\nset -eu\n"),
+            ScriptFormat::PowerShell => content.push_str("$ErrorActionPreference = 'Stop'\n"),
+        }
+        let mut last_reason = None;
+        for (removed, reason) in &self.plan {
+            let removed = removed
+                .to_str()
+                .with_context(|| format!("Cannot quote non-UTF-8 path {}", removed.display()))?;
+            if last_reason != Some(reason) {
+                writeln!(content, "\n# {}", reason.replace('\n', " ")).unwrap();
+                last_reason = Some(reason);
+            }
+            match format {
+                ScriptFormat::Sh => {
+                    writeln!(content, "rm -rf -- '{}'", removed.replace('\'', "'\\''")).unwrap()
+                }
+                ScriptFormat::PowerShell => {
+                    // PowerShell also takes typographic single quotes as quotes.
+                    let mut quoted = String::new();
+                    for c in removed.chars() {
+                        if let '\'' | '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{201b}' = c {
+                            quoted.push(c);
+                        }
+                        quoted.push(c);
+                    }
+                    writeln!(
+                        content,
+                        "if (Test-Path -LiteralPath '{0}') {{ Remove-Item -LiteralPath '{0}' -Recurse -Force }}",
+                        quoted,
+                    )
+                    .unwrap()
+                }
+            }
+        }
+        fs::write(path, content)
+            .with_context(|| format!("Failed to write script to {}", path.display()))?;
+        #[cfg(unix)]
+        if format == ScriptFormat::Sh {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+}
+
+/// Read `keep` of a document in the same schema. Relative paths are resolved against
+/// `target_dir`.
+pub fn read_keep_list(path: &Path, target_dir: &Path) -> Result<Vec<PathBuf>> {
+    let doc = fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+        .with_context(|| format!("Invalid keep-list {}", path.display()))?;
+    ensure!(
+        doc.get("schema").and_then(|v| v.as_str()) == Some(SCHEMA)
+            && doc.get("version").and_then(|v| v.as_u64()) == Some(VERSION),
+        "Keep-list {} is not `{}` version {}",
+        path.display(),
+        SCHEMA,
+        VERSION,
+    );
+    let keep = doc.get("keep").and_then(|v| v.as_array());
+    Ok(keep
+        .into_iter()
+        .flatten()
+        .filter_map(|v| v.as_str())
+        .map(|p| target_dir.join(p))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remove::Remover;
+    use cargo::Config;
+    use std::env;
+
+    /// Plan removal of `{dir}/debug` in dry-run, where `debug/deps/kept` is protected.
+    fn plan(dir: &Path) -> Report {
+        let deps = dir.join("debug").join("deps");
+        fs::create_dir_all(&deps).unwrap();
+        fs::write(deps.join("kept"), "").unwrap();
+        fs::write(deps.join("stale"), "").unwrap();
+        fs::write(dir.join("debug").join("stale"), "").unwrap();
+
+        let config = Config::default().unwrap();
+        let mut remover = Remover::new(&config, true);
+        remover.plan = Some(Vec::new());
+        remover.protected.insert(deps.join("kept"));
+        remover.remove(&dir.join("debug"), "unreachable").unwrap();
+        Report {
+            dry_run: true,
+            caches: Vec::new(),
+            plan: remover.plan.take().unwrap(),
+            keep: remover.protected.iter().cloned().collect(),
+        }
+    }
+
+    #[test]
+    fn script_excludes_protected() {
+        let dir = env::temp_dir().join(format!("cargo-gc-script-{}", std::process::id()));
+        let report = plan(&dir);
+        let script = dir.join("gc.sh");
+        report.write_script(&script, ScriptFormat::Sh).unwrap();
+        let content = fs::read_to_string(&script).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut removed = content
+            .lines()
+            .filter_map(|line| line.strip_prefix("rm -rf -- '")?.strip_suffix('\''))
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        removed.sort();
+        let debug = dir.join("debug");
+        let expected = vec![debug.join("deps").join("stale"), debug.join("stale")];
+        // Neither the protected file nor its ancestors are removed.
+        assert_eq!(removed, expected);
+    }
+
+    #[test]
+    fn json_plan_excludes_protected() {
+        let dir = env::temp_dir().join(format!("cargo-gc-json-plan-{}", std::process::id()));
+        let doc = plan(&dir).to_json();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(doc["schema"], SCHEMA);
+        assert_eq!(doc["version"], VERSION);
+        let paths = |key: &str| {
+            doc[key]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|v| PathBuf::from(v.get("path").unwrap_or(v).as_str().unwrap()))
+                .collect::<Vec<_>>()
+        };
+        let (planned, keep) = (paths("plan"), paths("keep"));
+        assert_eq!(planned.len(), 2, "{:?}", planned);
+        assert_eq!(keep, [dir.join("debug").join("deps").join("kept")]);
+        for path in &planned {
+            assert!(
+                !keep.iter().any(|kept| kept.starts_with(path)),
+                "{:?}",
+                path
+            );
+        }
+    }
+}
@@ -1,18 +1,57 @@
-use anyhow::{ensure, Context as _, Result};
+use anyhow::{bail, ensure, Context as _, Result};
+use bytesize::ByteSize;
 use cargo::{
-    core::Workspace, util::important_paths::find_root_manifest_for_wd, CargoResult, Config,
+    core::{Shell, Workspace},
+    ops::load_pkg_lockfile,
+    util::important_paths::find_root_manifest_for_wd,
+    CargoResult, Config,
 };
+use filetime::FileTime;
+use notify::{RecursiveMode, Watcher as _};
 use semver::Version;
+use serde_json::json;
 use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     env,
-    ffi::OsStr,
-    fs,
+    ffi::{OsStr, OsString},
+    fs, io,
     path::{Path, PathBuf},
+    sync::mpsc,
+    time::{Duration, Instant, SystemTime},
 };
-use structopt::{clap::AppSettings, StructOpt};
+use structopt::{
+    clap::{self, arg_enum, AppSettings},
+    StructOpt,
+};
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
 
+mod branches;
 mod cargo_lto;
 mod collect;
+mod compact;
+mod daemon;
+mod dirty;
+mod discover;
+mod doctor;
+mod dupes;
+mod explain;
+mod fuzz;
+mod home;
+mod index;
+mod interop;
+mod metrics;
+mod nextest;
+mod ondisk;
+mod privileged;
+mod remove;
+mod selftest;
+mod size;
+mod strip;
+mod sweep;
+mod timing;
+mod toolchain;
+mod verify;
+mod why;
 
 #[derive(StructOpt)]
 #[structopt(bin_name = "cargo")]
@@ -22,8 +61,165 @@ enum CliOpts {
     Gc(CliArgs),
 }
 
+#[derive(StructOpt)]
+enum Command {
+    /// Print shell completion script to stdout.
+    Completions {
+        /// Target shell
+        #[structopt(possible_values = &clap::Shell::variants(), case_insensitive = true)]
+        shell: clap::Shell,
+    },
+    /// Watch the target directory size and GC when it exceeds the threshold.
+    Watch {
+        /// Size of the target directory to trigger GC, eg. `20GiB`
+        #[structopt(long = "threshold", value_name = "SIZE")]
+        threshold: ByteSize,
+        /// Interval between size checks
+        #[structopt(
+            long = "interval",
+            value_name = "DURATION",
+            default_value = "10min",
+            parse(try_from_str = humantime::parse_duration)
+        )]
+        interval: Duration,
+    },
+    /// Serve size queries and GC requests on a Unix socket, for editors and build tools.
+    Serve {
+        /// Path of the socket
+        #[structopt(long = "socket", value_name = "PATH")]
+        socket: PathBuf,
+    },
+    /// List kept artifacts of reachable units with their packages, feature sets,
+    /// profiles and targets.
+    Ls {
+        /// Only list artifacts of these packages
+        #[structopt(
+            short = "p",
+            long = "package",
+            value_name = "NAME",
+            number_of_values = 1
+        )]
+        packages: Vec<String>,
+        /// Only list artifacts in these profile directories, eg. `debug`
+        #[structopt(long = "profile", value_name = "NAME", number_of_values = 1)]
+        profiles: Vec<String>,
+    },
+    /// Explain why artifacts became unreachable, by comparing their fingerprints with those of
+    /// reachable builds of the same packages.
+    ExplainStale {
+        /// Stale entries, eg. `debug/.fingerprint/foo-0123456789abcdef` or
+        /// `debug/deps/libfoo-0123456789abcdef.rlib`, relative to the target directory
+        #[structopt(value_name = "ENTRY", required = true)]
+        entries: Vec<PathBuf>,
+    },
+    /// Compare on-disk fingerprints of units of a package with the current build field by
+    /// field, to find out why cargo keeps rebuilding it.
+    FingerprintDiff {
+        #[structopt(value_name = "PACKAGE")]
+        package: String,
+        /// Only compare units in these profile directories, eg. `debug`
+        #[structopt(long = "profile", value_name = "NAME", number_of_values = 1)]
+        profiles: Vec<String>,
+    },
+    /// Report which packages and features account for the size of the target directory.
+    WhyLarge {
+        /// Number of the largest entries to show for each profile
+        #[structopt(long = "top", value_name = "N", default_value = "20")]
+        top: usize,
+    },
+    /// Report packages with artifacts of multiple versions.
+    Duplicates {
+        /// Suggest dependents to upgrade for unifying reachable versions
+        #[structopt(long = "dedupe-suggestions")]
+        dedupe_suggestions: bool,
+    },
+    /// Diagnose why the target directory is large and churny, and suggest configurations.
+    Doctor,
+    /// Build a throwaway project with your cargo, GC it, rebuild it and check that nothing is
+    /// recompiled, to validate that hashes of cargo-gc match your toolchain
+    SelfTest {
+        /// Keep the project for inspection instead of removing it
+        #[structopt(long = "keep")]
+        keep: bool,
+    },
+    /// GC the cargo home, keeping entries referenced by lock files of known projects.
+    Home(HomeArgs),
+    /// Add workspaces to the index of projects known to `cargo gc home`.
+    Register {
+        /// Workspace directories. Defaults to the current directory
+        #[structopt(value_name = "DIR")]
+        dirs: Vec<PathBuf>,
+    },
+    /// Remove workspaces from the index of projects known to `cargo gc home`.
+    Unregister {
+        /// Workspace directories. Defaults to the current directory
+        #[structopt(value_name = "DIR")]
+        dirs: Vec<PathBuf>,
+    },
+    /// List workspaces in the index of projects known to `cargo gc home`.
+    List,
+    /// Remove all artifacts of packages in all profiles and targets, reachable or not,
+    /// to force rebuilding them.
+    Purge {
+        /// Package to purge
+        #[structopt(
+            short = "p",
+            long = "package",
+            value_name = "SPEC",
+            required = true,
+            number_of_values = 1
+        )]
+        packages: Vec<String>,
+    },
+}
+
+/// Options of the cargo home GC, separated from those of the target directory GC.
+#[derive(StructOpt)]
+struct HomeArgs {
+    /// Do not actually remove files or directories
+    #[structopt(long = "dry-run")]
+    dry_run: bool,
+    /// Print sizes of each category in the cargo home
+    #[structopt(long = "stats")]
+    stats: bool,
+    /// Keep unreferenced entries modified within AGE, eg. `30days`
+    #[structopt(
+        long = "max-age",
+        value_name = "AGE",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    max_age: Option<Duration>,
+    /// Select all categories below
+    #[structopt(long = "all")]
+    all: bool,
+    /// Remove `.crate` files in `registry/cache` not referenced by any known lock file
+    #[structopt(long = "registry-cache")]
+    registry_cache: bool,
+    /// Remove extracted sources in `registry/src` not referenced by any known lock file,
+    /// or whose `.crate` file is gone
+    #[structopt(long = "registry-src")]
+    registry_src: bool,
+    /// Remove checkouts in `git/checkouts` at revisions not referenced by any known
+    /// lock file
+    #[structopt(long = "git-checkouts")]
+    git_checkouts: bool,
+    /// Remove repositories in `git/db` without any remaining checkout, and prune the
+    /// others by `git gc`
+    #[structopt(long = "git-db")]
+    git_db: bool,
+    /// Project directory whose `Cargo.lock` is known. Defaults to the current directory
+    #[structopt(long = "project", value_name = "DIR", number_of_values = 1)]
+    projects: Vec<PathBuf>,
+    /// Find lock files under project directories recursively
+    #[structopt(long = "recursive")]
+    recursive: bool,
+}
+
 #[derive(StructOpt)]
 struct CliArgs {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+
     /// Path to Cargo.toml
     #[structopt(long = "manifest-path", value_name = "PATH", parse(from_os_str))]
     manifest_path: Option<PathBuf>,
@@ -31,13 +227,314 @@ struct CliArgs {
     /// This will skip the out-of-workspace check for target directory
     #[structopt(long = "target-dir", value_name = "DIR", parse(from_os_str))]
     target_dir: Option<PathBuf>,
+    /// GC `--target-dir` without a workspace, using only on-disk information.
+    /// For each package, only variants built around its latest build are kept
+    #[structopt(long = "no-manifest", requires = "target-dir")]
+    no_manifest: bool,
     /// Do not actually remove files or directories.
     #[structopt(long = "dry-run")]
     dry_run: bool,
 
-    /// Force GC without checking cargo version or out-of-workspace target directory.
+    /// Search the repository for other workspaces sharing the target directory, and keep their
+    /// artifacts as well
+    #[structopt(long = "discover")]
+    discover: bool,
+    /// Only register the workspace as an owner of the target directory without GC.
+    /// Artifacts of all registered owners are kept
+    #[structopt(long = "register")]
+    register: bool,
+    /// Verify that artifacts on disk match computed reachable units before removing anything.
+    #[structopt(long = "check")]
+    check: bool,
+    /// Run `cargo check`, or CMD if given, after GC and fail if anything is rebuilt
+    #[structopt(long = "verify-after", value_name = "CMD")]
+    verify_after: Option<Option<String>>,
+    /// The cargo executable in use, instead of `$CARGO`
+    #[structopt(long = "cargo", value_name = "PATH", parse(from_os_str))]
+    cargo: Option<PathBuf>,
+    /// Force GC without any safety checks. Same as all `--allow-*` flags.
     #[structopt(long = "force", short = "f")]
     force: bool,
+    /// Allow GC when the cargo version differs from the library used by cargo-gc
+    #[structopt(long = "allow-version-mismatch")]
+    allow_version_mismatch: bool,
+    /// Allow GC when registered owners of the target directory cannot be loaded
+    #[structopt(long = "allow-shared-target-dir")]
+    allow_shared_target_dir: bool,
+    /// Allow GC when the target directory is outside the workspace and repository
+    #[structopt(long = "allow-external-target-dir")]
+    allow_external_target_dir: bool,
+    /// Continue on IO errors of individual entries, report them at the end
+    /// and exit with code 2.
+    #[structopt(long = "keep-going")]
+    keep_going: bool,
+    /// Schedule files locked by other processes for deletion on next reboot (Windows only)
+    #[structopt(long = "defer-locked")]
+    defer_locked: bool,
+
+    /// Also remove coverage outputs: `*.profraw`, `*.profdata`,
+    /// and `coverage`, `llvm-cov` directories under the target directory
+    #[structopt(long = "coverage")]
+    coverage: bool,
+    /// Only remove coverage outputs not modified within AGE, eg. `7days`
+    #[structopt(
+        long = "coverage-max-age",
+        value_name = "AGE",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    coverage_max_age: Option<Duration>,
+    /// Remove whole profile directories, like `release`, not used within AGE, eg. `30days`
+    #[structopt(
+        long = "prune-unused-profiles",
+        value_name = "AGE",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    prune_unused_profiles: Option<Duration>,
+    /// Skip GC if the last successful run on the target directory is within DURATION, eg. `1h`
+    #[structopt(
+        long = "min-interval",
+        value_name = "DURATION",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    min_interval: Option<Duration>,
+    /// Write metrics of the run to PATH
+    #[structopt(long = "metrics-file", value_name = "PATH", parse(from_os_str))]
+    metrics_file: Option<PathBuf>,
+    /// Format of `--metrics-file`. Prometheus textfiles are overwritten while JSON lines are appended
+    #[structopt(
+        long = "metrics-format",
+        value_name = "FORMAT",
+        default_value = "prometheus",
+        possible_values = &metrics::Format::variants(),
+        case_insensitive = true
+    )]
+    metrics_format: metrics::Format,
+    /// Format of logs enabled by `RUST_LOG`. `json` also logs durations of spans
+    #[structopt(
+        long = "log-format",
+        value_name = "FORMAT",
+        default_value = "text",
+        possible_values = &LogFormat::variants(),
+        case_insensitive = true
+    )]
+    log_format: LogFormat,
+    /// Write caches, their sizes and removed paths with reasons as JSON to PATH, for other
+    /// tools to consume. Use with `--dry-run` to only plan
+    #[structopt(long = "plan-file", value_name = "PATH")]
+    plan_file: Option<PathBuf>,
+    /// Write a script with commands removing paths of the plan to PATH, to be reviewed or run
+    /// elsewhere. Use with `--dry-run` to only plan
+    #[structopt(long = "script", value_name = "PATH")]
+    script: Option<PathBuf>,
+    /// Shell of `--script`
+    #[structopt(
+        long = "script-format",
+        value_name = "FORMAT",
+        default_value = "sh",
+        possible_values = &interop::ScriptFormat::variants(),
+        case_insensitive = true
+    )]
+    script_format: interop::ScriptFormat,
+    /// Keep paths listed in `keep` of a JSON file in the schema of `--plan-file`
+    #[structopt(long = "keep-list", value_name = "PATH")]
+    keep_list: Option<PathBuf>,
+    /// Report time spent in each phase, like resolution, unit scan and deletion
+    #[structopt(long = "profile-self")]
+    profile_self: bool,
+    /// Also keep artifacts of the latest N builds of each package, even if unreachable
+    #[structopt(long = "keep-latest", value_name = "N")]
+    keep_latest: Option<usize>,
+    /// Keep uplifted binaries in profile directories, like `target/release/<bin>`, even if
+    /// unreachable, and the latest N builds of each of them in `deps`
+    #[structopt(long = "keep-uplifted", value_name = "N")]
+    keep_uplifted: Option<usize>,
+    /// Never remove uplifted files in profile directories, like `target/release/<bin>`.
+    /// Also enabled by `gc.preserve-uplifted = true` in `.cargo/config.toml`
+    #[structopt(long = "preserve-uplifted")]
+    preserve_uplifted: bool,
+    /// Also keep files with extension EXT post-processed from kept uplifted binaries, like
+    /// `target/release/<bin>.EXT`, besides `gc.post-process-extensions` or the built-in list
+    #[structopt(long = "post-process-ext", value_name = "EXT", number_of_values = 1)]
+    post_process_exts: Vec<String>,
+    /// Strip debug info from libraries and binaries in `deps` not modified within AGE,
+    /// eg. `14days`, instead of keeping them intact. They are rebuilt when used again
+    #[structopt(
+        long = "strip-debuginfo",
+        value_name = "AGE",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    strip_debuginfo: Option<Duration>,
+    /// Also remove `.rmeta` of libraries whose `.rlib` exists. Builds using them may
+    /// re-check these libraries
+    #[structopt(long = "aggressive-rmeta")]
+    aggressive_rmeta: bool,
+    /// Record reachable artifacts per git branch, and keep those of branches with commits
+    /// within AGE, eg. `14days`
+    #[structopt(
+        long = "branch-retention",
+        value_name = "AGE",
+        parse(try_from_str = humantime::parse_duration)
+    )]
+    branch_retention: Option<Duration>,
+    /// Only remove artifacts recorded by `--branch-retention` for branches no longer existing,
+    /// and not used by the current workspace or other branches
+    #[structopt(long = "deleted-branches")]
+    deleted_branches: bool,
+    /// Number of threads to unlink files, and to walk directories for sizes with
+    #[structopt(short = "j", long = "jobs", value_name = "N", default_value = "1")]
+    jobs: usize,
+    /// Limit removals per second to RATE entries, eg. `500`, or bytes, eg. `50MiB`
+    #[structopt(long = "throttle", value_name = "RATE")]
+    throttle: Option<remove::Throttle>,
+    /// Also report sizes remaining in each category, and numbers of units and artifacts
+    /// kept in each profile
+    #[structopt(long = "report-kept")]
+    report_kept: bool,
+    /// Skip size accounting and report only numbers of removed files and directories,
+    /// which is faster where stat is expensive
+    #[structopt(long = "no-size")]
+    no_size: bool,
+    /// Tune for the filesystem of the target directory. `network` is for NFS or SMB, where
+    /// each stat is a round trip. It implies `--no-size` and at least 16 `--jobs`
+    #[structopt(
+        long = "fs-profile",
+        value_name = "PROFILE",
+        default_value = "local",
+        possible_values = &FsProfile::variants(),
+        case_insensitive = true
+    )]
+    fs_profile: FsProfile,
+    /// Report each removal with its reason, eg. with `--dry-run` to audit what would be removed
+    #[structopt(long = "why")]
+    why: bool,
+    /// Keep artifacts of all workspace members instead of only `default-members`
+    #[structopt(long = "workspace")]
+    workspace: bool,
+    /// Only keep artifacts of units built in these comma-separated modes, and their
+    /// dependencies. Others, eg. test and bench artifacts, are collected
+    #[structopt(
+        long = "modes",
+        value_name = "MODES",
+        use_delimiter = true,
+        possible_values = &collect::Mode::variants(),
+        case_insensitive = true
+    )]
+    modes: Vec<collect::Mode>,
+    /// Keep artifacts built with these features instead of all features, and skip targets
+    /// whose `required-features` are not enabled
+    #[structopt(long = "features", value_name = "FEATURES", number_of_values = 1)]
+    features: Vec<String>,
+    /// Keep artifacts built without default features instead of all features
+    #[structopt(long = "no-default-features")]
+    no_default_features: bool,
+    /// Ignore `rust-version` of packages, if you build with `--ignore-rust-version`
+    #[structopt(long = "ignore-rust-version")]
+    ignore_rust_version: bool,
+    /// Also keep artifacts built by rustup toolchain NAME, eg. `nightly`
+    #[structopt(long = "toolchain", value_name = "NAME", number_of_values = 1)]
+    toolchains: Vec<String>,
+    /// List unknown entries in the target root, and remove them after confirmation
+    #[structopt(long = "sweep-unknown")]
+    sweep_unknown: bool,
+    /// Never treat entries in the target root matching GLOB as unknown
+    #[structopt(long = "keep-glob", value_name = "GLOB", number_of_values = 1)]
+    keep_globs: Vec<glob::Pattern>,
+    /// Assume yes for confirmations
+    #[structopt(long = "yes", short = "y")]
+    yes: bool,
+    /// Only remove stale artifacts of these packages, and leave others untouched
+    #[structopt(long = "crate", value_name = "NAME", number_of_values = 1)]
+    crates: Vec<String>,
+    /// Remove entries in a stable order and reset modification times of remaining directories,
+    /// to `SOURCE_DATE_EPOCH` if set or the Unix epoch otherwise, for reproducible container layers
+    #[structopt(long = "deterministic")]
+    deterministic: bool,
+    /// Only GC host artifacts, ie. build scripts, proc-macros and packages only used by them.
+    /// Packages also used by target units are left untouched
+    #[structopt(long = "host-only", conflicts_with = "no-host")]
+    host_only: bool,
+    /// Do not GC host artifacts, only GC those of packages only built for targets
+    #[structopt(long = "no-host")]
+    no_host: bool,
+    /// Keep `target/criterion` and the latest unreachable executable of each bench target,
+    /// so saved criterion baselines remain usable
+    #[structopt(long = "bench-aware")]
+    bench_aware: bool,
+    /// Also remove contents of `target/tmp` used by integration tests, unless modified within
+    /// an hour
+    #[structopt(long = "tmp")]
+    tmp: bool,
+    /// Also prune registry and git entries in the cargo home not referenced by the lock file
+    /// of the workspace, or of projects registered by `cargo gc register`
+    #[structopt(long = "include-registry")]
+    include_registry: bool,
+    /// Also GC the target directory of `cargo fuzz` in `fuzz/`
+    #[structopt(long = "fuzz")]
+    fuzz: bool,
+    /// The toolchain `cargo fuzz` runs with
+    #[structopt(
+        long = "fuzz-toolchain",
+        value_name = "TOOLCHAIN",
+        default_value = "nightly"
+    )]
+    fuzz_toolchain: String,
+    /// Keep test binaries listed in binaries metadata of cargo-nextest, as in archives
+    /// or output by `cargo nextest list --list-type binaries-only --message-format json`
+    #[structopt(long = "nextest", value_name = "PATH", number_of_values = 1)]
+    nextest: Vec<PathBuf>,
+    /// Rebuild each profile directory with hard links of reachable artifacts and swap it in
+    /// atomically, then remove the old one in the background. Linux only
+    #[structopt(long = "compact")]
+    compact: bool,
+    /// Finally remove empty directories left in `deps`, `build`, `.fingerprint`, `examples`
+    /// and `incremental` of each profile
+    #[structopt(long = "prune-empty-dirs")]
+    prune_empty_dirs: bool,
+    /// How to remove paths without permission, eg. owned by root after builds in containers of
+    /// `cross`: skip and list them, remove them by `--privileged-helper`, or in a container of
+    /// `--container-image`
+    #[structopt(
+        long = "on-permission-denied",
+        value_name = "ACTION",
+        default_value = "skip",
+        possible_values = &OnPermissionDenied::variants(),
+        case_insensitive = true
+    )]
+    on_permission_denied: OnPermissionDenied,
+    /// Command prefixing `rm` for `--on-permission-denied helper`
+    #[structopt(long = "privileged-helper", value_name = "CMD", default_value = "sudo")]
+    privileged_helper: String,
+    /// Image of containers for `--on-permission-denied container`, usually the image `cross`
+    /// builds in. They are run by `$CROSS_CONTAINER_ENGINE` or `docker`
+    #[structopt(long = "container-image", value_name = "IMAGE")]
+    container_image: Option<String>,
+    /// Also remove copies of artifacts in DIR, as made by `--out-dir` or `--artifact-dir` of
+    /// cargo, which are no longer identical to any remaining uplifted file
+    #[structopt(long = "artifact-dir", value_name = "DIR", number_of_values = 1)]
+    artifact_dirs: Vec<PathBuf>,
+    /// Also remove reachable artifacts which cargo rebuilds anyway, since sources or files
+    /// watched by build scripts of them or their dependencies changed after the build
+    #[structopt(long = "prune-dirty")]
+    prune_dirty: bool,
+    /// Also remove `target/rls` and `save-analysis` directories left by legacy toolchains
+    #[structopt(long = "legacy")]
+    legacy: bool,
+    /// Report artifacts of packages no longer in the lock file as their own category. Builds
+    /// kept by retention options are still kept
+    #[structopt(long = "orphans")]
+    orphans: bool,
+    /// Also remove ICE dumps of rustc, `*.mm_profdata`, `*.orig`, `*.rej` and stray `*.tmp`
+    /// files not modified within an hour
+    #[structopt(long = "temps")]
+    temps: bool,
+    /// Also remove leftovers of lock files after crashes, like `.cargo-lock.tmp`, which no
+    /// process holds. Lock files themselves are never removed
+    #[structopt(long = "locks")]
+    locks: bool,
+    /// Also remove documentations in `doc` directories generated by toolchains other than the
+    /// current one and those of `--toolchain`, which cargo wipes on the next doc build anyway
+    #[structopt(long = "stale-doc")]
+    stale_doc: bool,
 
     /// Increase verbosity
     #[structopt(long = "verbose", short = "v", parse(from_occurrences))]
@@ -51,72 +548,1618 @@ struct CliArgs {
     /// Require Cargo.lock and cache are up to date
     #[structopt(long = "frozen")]
     frozen: bool,
-    /// Require Cargo.lock is up to date
-    #[structopt(long = "locked")]
+    /// Require Cargo.lock is up to date. Always enforced, kept for compatibility
+    #[structopt(long = "locked", hidden = true)]
+    #[allow(dead_code)]
     locked: bool,
     /// Do not access the network
     #[structopt(long = "offline")]
     offline: bool,
+    /// Never access the network, and fail early listing packages missing from the local cache.
+    /// Implies `--frozen`
+    #[structopt(long = "no-network")]
+    no_network: bool,
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum LogFormat {
+        Text,
+        Json,
+    }
+}
 
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum OnPermissionDenied {
+        Skip,
+        Helper,
+        Container,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum FsProfile {
+        Local,
+        Network,
+    }
+}
+
+/// Minimal number of threads with `--fs-profile network`. Removals there are bound by
+/// latency rather than CPUs or disks.
+const NETWORK_JOBS: usize = 16;
+
+/// Log to stderr, filtered by `RUST_LOG`. Logs of cargo via `log` are also captured.
+fn init_logging(format: LogFormat) {
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(io::stderr);
+    match format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().with_span_events(FmtSpan::CLOSE).init(),
+    }
+}
+
+fn main() -> Result<()> {
     let CliOpts::Gc(args) = CliOpts::from_args();
+    init_logging(args.log_format);
+
+    match &args.command {
+        Some(Command::Completions { shell }) => {
+            CliOpts::clap().gen_completions_to("cargo", *shell, &mut std::io::stdout());
+            return Ok(());
+        }
+        _ => {}
+    }
 
-    if !args.force {
-        assert_cargo_version()?;
+    // Self-tests are meant to validate mismatched versions.
+    let is_self_test = matches!(args.command, Some(Command::SelfTest { .. }));
+    if !args.force && !args.allow_version_mismatch && !is_self_test {
+        assert_cargo_version(&args)?;
     }
 
+    let start = Instant::now();
+    if args.profile_self {
+        timing::enable();
+    }
     let mut config = Config::default()?;
-    config.configure(
-        args.verbose,
-        args.quiet,
-        args.color.as_deref(),
-        args.frozen,
-        args.locked,
-        args.offline,
-        &args.target_dir,
-        &[],
-        &[],
+    configure(&mut config, &args)?;
+
+    let ret = match &args.command {
+        Some(Command::Watch {
+            threshold,
+            interval,
+        }) => watch(&config, &args, *threshold, *interval),
+        Some(Command::Serve { socket }) => serve(&config, &args, socket),
+        Some(Command::Purge { packages }) => purge(&config, &args, packages),
+        Some(Command::Ls { packages, profiles }) => ls(&config, &args, packages, profiles),
+        Some(Command::ExplainStale { entries }) => explain_stale(&config, &args, entries),
+        Some(Command::FingerprintDiff { package, profiles }) => {
+            fingerprint_diff(&config, &args, package, profiles)
+        }
+        Some(Command::WhyLarge { top }) => why_large(&config, &args, *top),
+        Some(Command::Duplicates { dedupe_suggestions }) => {
+            duplicates(&config, &args, *dedupe_suggestions)
+        }
+        Some(Command::Doctor) => doctor(&config, &args),
+        Some(Command::SelfTest { keep }) => self_test(&config, &args, *keep),
+        Some(Command::Home(home_args)) => home(&config, &args, home_args),
+        Some(Command::Register { dirs }) => update_index(&config, dirs, true),
+        Some(Command::Unregister { dirs }) => update_index(&config, dirs, false),
+        Some(Command::List) => list_index(),
+        _ => run(&config, &args),
+    };
+    timing::report(&config, start.elapsed())?;
+    match ret {
+        Err(err) if err.downcast_ref::<PartialFailure>().is_some() => std::process::exit(2),
+        ret => ret,
+    }
+}
+
+/// Its modification time is the time of the last successful run.
+const LAST_RUN_FILE: &str = ".cargo-gc-last-run";
+
+/// Errors on individual entries are tolerated by `--keep-going`.
+#[derive(Debug)]
+struct PartialFailure;
+
+impl std::fmt::Display for PartialFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Some entries failed to be removed")
+    }
+}
+
+impl std::error::Error for PartialFailure {}
+
+fn watch(config: &Config, args: &CliArgs, threshold: ByteSize, interval: Duration) -> Result<()> {
+    let target_dir = resolve_target_dir(config, args)?;
+    config.shell().status(
+        "Watching",
+        format_args!(
+            "`{}` with threshold {}",
+            target_dir.display(),
+            threshold.to_string_as(true),
+        ),
     )?;
+    fs::create_dir_all(&target_dir)?;
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, Duration::from_secs(2))?;
+    watcher.watch(&target_dir, RecursiveMode::Recursive)?;
+    // Removals during GC are reported as notifications as well, and overflowed event
+    // queues lead to a full rescan.
+    let mut inventory = size::Inventory::scan(&target_dir)?;
+    loop {
+        for event in rx.try_iter() {
+            inventory.handle(&target_dir, event)?;
+        }
+        let size = inventory.total();
+        tracing::debug!("Target directory size: {}", size);
+        if size > threshold.as_u64() {
+            config.shell().status(
+                "Triggered",
+                format_args!(
+                    "target directory size is {}",
+                    ByteSize(size).to_string_as(true)
+                ),
+            )?;
+            if let Err(err) = run(config, args) {
+                config.shell().error(format_args!("{:?}", err))?;
+            }
+        }
+        std::thread::sleep(interval);
+    }
+}
+
+fn serve(config: &Config, args: &CliArgs, socket: &Path) -> Result<()> {
+    let target_dir = resolve_target_dir(config, args)?;
+    let socket = config.cwd().join(socket);
+    config.shell().status(
+        "Listening",
+        format_args!("on {} for `{}`", socket.display(), target_dir.display()),
+    )?;
+    fs::create_dir_all(&target_dir)?;
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::watcher(tx, Duration::from_secs(2))?;
+    watcher.watch(&target_dir, RecursiveMode::Recursive)?;
+    let mut inventory = size::Inventory::scan(&target_dir)?;
+    let mut last_plan = None::<interop::Report>;
+    daemon::serve(&socket, |request| match request {
+        daemon::Request::Size => {
+            for event in rx.try_iter() {
+                inventory.handle(&target_dir, event)?;
+            }
+            Ok(json!({ "size": inventory.total() }))
+        }
+        daemon::Request::Plan => Ok(json!({ "plan": last_plan.as_ref().map(|r| r.to_json()) })),
+        daemon::Request::Gc { args } => {
+            let argv = ["cargo", "gc"].iter().map(|s| s.to_string()).chain(args);
+            let CliOpts::Gc(args) = CliOpts::from_iter_safe(argv)?;
+            ensure!(
+                args.command.is_none(),
+                "Subcommands are not allowed in requests"
+            );
+            let mut config = Config::default()?;
+            configure(&mut config, &args)?;
+            let report = gc_once(&config, &args, true)?;
+            let resp = json!({ "plan": report.as_ref().map(|r| r.to_json()) });
+            if report.is_some() {
+                last_plan = report;
+            }
+            Ok(resp)
+        }
+    })
+}
+
+fn purge(config: &Config, args: &CliArgs, packages: &[String]) -> Result<()> {
+    let target_dir = resolve_target_dir(config, args)?;
+    let mut remover = remove::Remover::new(config, args.dry_run);
+    remover.keep_going = args.keep_going;
+    remover.why = args.why;
+    remover.throttle = args.throttle;
+    remover.jobs = jobs(args);
+    remover.no_size = no_size(args);
+    for dir in ondisk::profile_dirs(&target_dir)? {
+        let display_dir = dir.strip_prefix(&target_dir).unwrap_or(&dir);
+        config.shell().status("Purging", display_dir.display())?;
+        let hashes = ondisk::package_hashes(&dir, packages)?;
+        // Crate names may differ from package names, eg. with `[lib] name = "..."`.
+        let mut crates = ondisk::crate_names(&dir, &hashes)?;
+        crates.extend(packages.iter().map(|name| name.replace('-', "_")));
+        let mut reachable = collect::Reachable::default();
+        reachable.restrict(hashes);
+        reachable.purged = true;
+        remove_unreachable(&dir, &reachable, &mut remover)?;
+
+        // `incremental/{crate}-{hash}`, where the hash is not the metadata hash.
+        let incremental_dir = dir.join("incremental");
+        if incremental_dir.is_dir() {
+            for entry in remover.read_dir(&incremental_dir)? {
+                let name = entry.file_name();
+                let crate_name = name
+                    .to_str()
+                    .and_then(|name| name.rfind('-').map(|idx| &name[..idx]));
+                if crate_name.map_or(false, |name| crates.contains(name)) {
+                    remover.remove(&entry.path(), "incremental cache of purged package")?;
+                }
+            }
+        }
+    }
+    report_finished(config, &remover, "")?;
+    if !remover.failures.is_empty() {
+        return Err(PartialFailure.into());
+    }
+    Ok(())
+}
+
+fn ls(config: &Config, args: &CliArgs, packages: &[String], profiles: &[String]) -> Result<()> {
+    let ws = load_workspace(&root_manifest_path(args)?, config)?;
+    let target_dir = resolve_target_dir(config, args)?;
+    let ws_profiles = collect::profiles(&ws)?;
+    for (triple, triple_dir) in &triple_dirs(&target_dir)? {
+        for (profile, display_profile) in &ws_profiles {
+            let dir = triple_dir.join(display_profile);
+            if !profiles.is_empty() && !profiles.iter().any(|p| p == display_profile)
+                || !dir.join(".fingerprint").is_dir()
+            {
+                continue;
+            }
+            let targets = match triple {
+                Some(triple) => std::slice::from_ref(triple),
+                None => &[],
+            };
+            let mut reachable = collect::Reachable::default();
+            collect::collect_workspace_units(
+                config,
+                &ws,
+                targets,
+                profile,
+                &selection(args),
+                &mut reachable,
+            )?;
+            let triple = triple.as_deref().unwrap_or("host");
+            // Units of multiple targets of a package may share the directory.
+            let mut listed = BTreeSet::new();
+            for node in &reachable.graph {
+                if !packages.is_empty() && !packages.contains(&node.name)
+                    || !dir.join(".fingerprint").join(&node.pkg_dir).is_dir()
+                    || !listed.insert(&node.pkg_dir)
+                {
+                    continue;
+                }
+                println!(
+                    "{} v{} ({}/{}) features {} [{}]{}",
+                    node.name,
+                    node.version,
+                    triple,
+                    display_profile,
+                    cargo::util::short_hash(&node.features),
+                    node.features.join(", "),
+                    if node.is_custom_build {
+                        " build script"
+                    } else {
+                        ""
+                    },
+                );
+                for file in &node.deps_files {
+                    if dir.join("deps").join(file).exists() {
+                        println!("  deps/{}", file);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The host directory and directories of target triples in `target_dir`.
+fn triple_dirs(target_dir: &Path) -> Result<Vec<(Option<String>, PathBuf)>> {
+    let mut ret = vec![(None, target_dir.to_owned())];
+    for name in ondisk::list_names(target_dir)? {
+        // Target triples like `x86_64-unknown-linux-gnu`, as in `gc_workspaces`.
+        if name.contains('-') && target_dir.join(&name).is_dir() {
+            ret.push((Some(name.clone()), target_dir.join(&name)));
+        }
+    }
+    Ok(ret)
+}
+
+fn fingerprint_diff(
+    config: &Config,
+    args: &CliArgs,
+    package: &str,
+    profiles: &[String],
+) -> Result<()> {
+    let ws = load_workspace(&root_manifest_path(args)?, config)?;
+    let target_dir = resolve_target_dir(config, args)?;
+    let rustc = cargo::util::hash_u64(&config.load_global_rustc(Some(&ws))?.verbose_version);
+    let ws_profiles = collect::profiles(&ws)?;
+    let mut found = false;
+    for (triple, triple_dir) in &triple_dirs(&target_dir)? {
+        for (profile, display_profile) in &ws_profiles {
+            let dir = triple_dir.join(display_profile);
+            if !profiles.is_empty() && !profiles.iter().any(|p| p == display_profile)
+                || !dir.join(".fingerprint").is_dir()
+            {
+                continue;
+            }
+            let targets = match triple {
+                Some(triple) => std::slice::from_ref(triple),
+                None => &[],
+            };
+            let mut reachable = collect::Reachable::default();
+            collect::collect_workspace_units(
+                config,
+                &ws,
+                targets,
+                profile,
+                &selection(args),
+                &mut reachable,
+            )?;
+            let triple = triple.as_deref().unwrap_or("host");
+            let mut compared = BTreeSet::new();
+            for node in reachable.graph.iter().filter(|node| node.name == package) {
+                if !compared.insert((&node.pkg_dir, &node.target_name)) {
+                    continue;
+                }
+                found = true;
+                let fingerprint_dir = dir.join(".fingerprint").join(&node.pkg_dir);
+                let fingerprints = explain::read(&fingerprint_dir).unwrap_or_default();
+                println!(
+                    "{} v{} ({}/{}) target {} in {}",
+                    node.name,
+                    node.version,
+                    triple,
+                    display_profile,
+                    node.target_name,
+                    node.pkg_dir,
+                );
+                let recorded = match explain::of_target(&fingerprints, &node.target_name) {
+                    Some((_, recorded)) => recorded,
+                    None => {
+                        println!("  not built, or built by a different cargo");
+                        continue;
+                    }
+                };
+                let computed = explain::computed(&node.features, rustc, &node.rustflags);
+                for (field, detail) in explain::compare(recorded, &computed) {
+                    println!("  {}: {}", field, detail.as_deref().unwrap_or("same"));
+                }
+                // Dependencies rebuilt since record their new hashes in their own fingerprints.
+                let recorded_deps = explain::dep_hashes(recorded);
+                let mut rebuilt = Vec::new();
+                for &dep in &node.deps {
+                    let dep = &reachable.graph[dep];
+                    let dep_dir = dir.join(".fingerprint").join(&dep.pkg_dir);
+                    match explain::read_hash(&dep_dir, &dep.target_name) {
+                        Some(hash) if recorded_deps.contains(&hash) => {}
+                        Some(_) => rebuilt.push(format!("{} (rebuilt)", dep.name)),
+                        None => rebuilt.push(format!("{} (not built)", dep.name)),
+                    }
+                }
+                rebuilt.sort();
+                rebuilt.dedup();
+                println!(
+                    "  dependencies (`deps`): {}",
+                    if rebuilt.is_empty() {
+                        "same".to_owned()
+                    } else {
+                        rebuilt.join(", ")
+                    },
+                );
+            }
+        }
+    }
+    if !found {
+        bail!("No reachable unit of package `{}`", package);
+    }
+    Ok(())
+}
+
+fn explain_stale(config: &Config, args: &CliArgs, entries: &[PathBuf]) -> Result<()> {
+    let ws = load_workspace(&root_manifest_path(args)?, config)?;
+    let target_dir = resolve_target_dir(config, args)?;
+    let rustc = cargo::util::hash_u64(&config.load_global_rustc(Some(&ws))?.verbose_version);
+    let ws_profiles = collect::profiles(&ws)?;
+    for entry in entries {
+        // `{profile directory}/{.fingerprint,build,deps}/{name}`
+        let path = target_dir.join(entry);
+        let (dir, name) = match (
+            path.parent().and_then(Path::parent),
+            path.file_name().and_then(|name| name.to_str()),
+        ) {
+            (Some(dir), Some(name)) => (dir, name),
+            _ => bail!("Invalid entry {}", path.display()),
+        };
+        let hash = name
+            .split('.')
+            .next()
+            .and_then(ondisk::split_hash)
+            .map(|(_, hash)| hash)
+            .with_context(|| format!("{} has no metadata hash", path.display()))?;
+        let fingerprint_name = ondisk::list_names(&dir.join(".fingerprint"))?
+            .into_iter()
+            .find(|name| ondisk::split_hash(name).map_or(false, |(_, h)| h == hash))
+            .with_context(|| format!("No fingerprint of {} in {}", hash, dir.display()))?;
+        let pkg = ondisk::split_hash(&fingerprint_name).unwrap().0;
+        let dir_name = dir.file_name().and_then(|name| name.to_str());
+        let profile = ws_profiles
+            .iter()
+            .find(|(_, display_profile)| Some(&**display_profile) == dir_name)
+            .map(|(profile, _)| profile)
+            .with_context(|| format!("No profile is built into {}", dir.display()))?;
+        let triple = dir
+            .parent()
+            .filter(|parent| *parent != target_dir)
+            .and_then(|parent| Some(parent.file_name()?.to_str()?.to_owned()));
+        let targets = match &triple {
+            Some(triple) => std::slice::from_ref(triple),
+            None => &[],
+        };
+        let mut reachable = collect::Reachable::default();
+        collect::collect_workspace_units(
+            config,
+            &ws,
+            targets,
+            profile,
+            &selection(args),
+            &mut reachable,
+        )?;
+
+        println!("{}:", entry.display());
+        if reachable.fingerprints.contains(&fingerprint_name) {
+            println!("  reachable, not stale");
+            continue;
+        }
+        let stale = explain::read(&dir.join(".fingerprint").join(&fingerprint_name))?;
+        let mut compared = BTreeSet::new();
+        for node in reachable.graph.iter().filter(|node| node.name == pkg) {
+            let (file, recorded) = match explain::of_target(&stale, &node.target_name) {
+                Some(fingerprint) => fingerprint,
+                None => continue,
+            };
+            if !compared.insert((&node.pkg_dir, &node.target_name)) {
+                continue;
+            }
+            let built =
+                explain::read(&dir.join(".fingerprint").join(&node.pkg_dir)).unwrap_or_default();
+            let (against, diffs) = match explain::of_target(&built, &node.target_name) {
+                Some((_, live)) => (node.pkg_dir.clone(), explain::diff(recorded, live)),
+                None => {
+                    let computed = explain::computed(&node.features, rustc, &node.rustflags);
+                    (
+                        format!("{} (not built yet)", node.pkg_dir),
+                        explain::diff(recorded, &computed),
+                    )
+                }
+            };
+            println!("  {} differs from reachable {} in:", file, against);
+            if diffs.is_empty() {
+                println!("    nothing compared, probably rebuilt by a different cargo");
+            }
+            for diff in diffs {
+                println!("    {}", diff);
+            }
+        }
+        if compared.is_empty() {
+            println!(
+                "  no reachable unit of package `{}` has the same target",
+                pkg
+            );
+        }
+    }
+    Ok(())
+}
+
+fn why_large(config: &Config, args: &CliArgs, top: usize) -> Result<()> {
+    for_each_profile(config, args, |display_profile, dir, reachable| {
+        // Account removable sizes without removing anything.
+        let mut remover = remove::Remover::new(config, true);
+        remove_unreachable(dir, reachable, &mut remover)?;
+
+        let usages = why::attribute(dir, reachable)?;
+        let kept = usages.values().map(|usage| usage.size).sum::<u64>();
+        println!(
+            "{}: {} kept by reachable units, {} removable",
+            display_profile,
+            ByteSize(kept).to_string_as(true),
+            ByteSize(remover.freed_bytes).to_string_as(true),
+        );
+        print!("{}", why::report(&usages, top));
+        Ok(())
+    })
+}
+
+fn duplicates(config: &Config, args: &CliArgs, suggestions: bool) -> Result<()> {
+    for_each_profile(config, args, |display_profile, dir, reachable| {
+        let duplicates = dupes::find(dir, reachable)?;
+        println!(
+            "{}: {} packages with multiple versions",
+            display_profile,
+            duplicates.len(),
+        );
+        print!("{}", dupes::report(&duplicates, suggestions));
+        Ok(())
+    })
+}
+
+fn home(config: &Config, args: &CliArgs, home_args: &HomeArgs) -> Result<()> {
+    let home_dir = config.home().clone().into_path_unlocked();
+    if home_args.stats {
+        for (category, size) in home::stats(&home_dir)? {
+            println!("{}: {}", category, ByteSize(size).to_string_as(true));
+        }
+    }
+    let selected = |flag| home_args.all || flag;
+    if ![
+        home_args.registry_cache,
+        home_args.registry_src,
+        home_args.git_checkouts,
+        home_args.git_db,
+    ]
+    .iter()
+    .any(|&flag| selected(flag))
+    {
+        ensure!(
+            home_args.stats,
+            "No category selected. Pass `--all` or some of `--registry-cache`, \
+`--registry-src`, `--git-checkouts` and `--git-db`.",
+        );
+        return Ok(());
+    }
+
+    let mut projects = home_args
+        .projects
+        .iter()
+        .map(|p| config.cwd().join(p))
+        .collect::<Vec<_>>();
+    if let Some(path) = index::path() {
+        projects.extend(index::load(&path)?);
+    }
+    if projects.is_empty() {
+        projects.push(config.cwd().to_owned());
+    }
+    let lockfiles = home::find_lockfiles(&projects, home_args.recursive);
+    config.shell().status(
+        "Collecting",
+        format_args!("references of {} lock files", lockfiles.len()),
+    )?;
+    config.shell().verbose(|s| {
+        s.status(
+            "Found",
+            format_args!("lock files:{}", list_paths(&lockfiles)),
+        )
+    })?;
+    let plan = home::Plan {
+        home: home_dir,
+        referenced: home::referenced(&lockfiles)?,
+        max_age: home_args.max_age,
+    };
+
+    // Never race a running cargo downloading or extracting packages.
+    let _lock = config.acquire_package_cache_lock()?;
+    // `cargo gc --dry-run home` is honored as well.
+    let dry_run = home_args.dry_run || args.dry_run;
+    let mut remover = remove::Remover::new(config, dry_run);
+    remover.why = args.why;
+    remover.no_size = no_size(args);
+    if selected(home_args.registry_cache) {
+        plan.gc_registry_cache(&mut remover)?;
+    }
+    if selected(home_args.registry_src) {
+        plan.gc_registry_src(&mut remover)?;
+    }
+    if selected(home_args.git_checkouts) {
+        plan.gc_git_checkouts(&mut remover)?;
+    }
+    if selected(home_args.git_db) {
+        plan.gc_git_db(&mut remover)?;
+    }
+    report_finished(config, &remover, "")
+}
+
+/// Prune registry and git entries in the cargo home not referenced by the lock file of the
+/// workspace, or of projects in the workspace index.
+fn gc_home_of_workspace(
+    config: &Config,
+    args: &CliArgs,
+    remover: &mut remove::Remover,
+) -> Result<()> {
+    let root_manifest = root_manifest_path(args)?;
+    let mut projects = vec![root_manifest.parent().unwrap().to_owned()];
+    if let Some(path) = index::path() {
+        projects.extend(index::load(&path)?);
+    }
+    let lockfiles = home::find_lockfiles(&projects, false);
+    let plan = home::Plan {
+        home: config.home().clone().into_path_unlocked(),
+        referenced: home::referenced(&lockfiles)?,
+        max_age: None,
+    };
+    config.shell().status(
+        "Pruning",
+        format_args!(
+            "cargo home entries not referenced by {} lock files",
+            lockfiles.len(),
+        ),
+    )?;
+    let _lock = config.acquire_package_cache_lock()?;
+    plan.gc_registry_cache(remover)?;
+    plan.gc_registry_src(remover)?;
+    plan.gc_git_checkouts(remover)?;
+    Ok(())
+}
+
+/// Add or remove `dirs`, or the current directory, in the workspace index.
+fn update_index(config: &Config, dirs: &[PathBuf], register: bool) -> Result<()> {
+    let path = index::path().context("Cannot determine the config directory")?;
+    let mut workspaces = index::load(&path)?;
+    let dirs = if dirs.is_empty() {
+        vec![config.cwd().to_owned()]
+    } else {
+        dirs.iter().map(|dir| config.cwd().join(dir)).collect()
+    };
+    for dir in dirs {
+        if register {
+            let dir = dir
+                .canonicalize()
+                .with_context(|| format!("Cannot register {}", dir.display()))?;
+            if !workspaces.contains(&dir) {
+                config.shell().status("Registering", dir.display())?;
+                workspaces.push(dir);
+            }
+        } else {
+            // Removed directories cannot be canonicalized.
+            let dir = dir.canonicalize().unwrap_or(dir);
+            if let Some(idx) = workspaces.iter().position(|p| *p == dir) {
+                config.shell().status("Unregistering", dir.display())?;
+                workspaces.remove(idx);
+            }
+        }
+    }
+    index::save(&path, &workspaces)
+}
+
+fn list_index() -> Result<()> {
+    if let Some(path) = index::path() {
+        for dir in index::load(&path)? {
+            println!("{}", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// Print the total freed size, or numbers of removed entries with `no_size`,
+/// followed by `extra` details.
+fn report_finished(config: &Config, remover: &remove::Remover, extra: &str) -> Result<()> {
+    let (amount, verb) = if remover.no_size {
+        let amount = format!(
+            "{} files and {} directories",
+            remover.removed_files, remover.removed_dirs,
+        );
+        (amount, "removed")
+    } else {
+        (ByteSize(remover.freed_bytes).to_string_as(true), "freed")
+    };
+    if remover.dry_run {
+        config.shell().status(
+            "Finished",
+            format_args!("{} can be {}{} (dry-run)", amount, verb, extra),
+        )?;
+    } else {
+        config
+            .shell()
+            .status("Finished", format_args!("{} {}{}", amount, verb, extra))?;
+    }
+    Ok(())
+}
+
+fn self_test(config: &Config, args: &CliArgs, keep: bool) -> Result<()> {
+    let cargo = cargo_exe(args).unwrap_or_else(|| "cargo".into());
+    let dir = env::temp_dir().join(format!("cargo-gc-self-test-{}", std::process::id()));
+    if dir.exists() {
+        fs::remove_dir_all(&dir)?;
+    }
+    config.shell().status("Creating", dir.display())?;
+    selftest::create_project(&dir)?;
+
+    let ret = (|| -> Result<()> {
+        for build in selftest::BUILDS {
+            selftest::run_cargo(config, &cargo, &dir, build)?;
+        }
+        selftest::bump_dependency(&dir)?;
+        selftest::run_cargo(config, &cargo, &dir, &["build"])?;
+
+        let manifest = dir.join("Cargo.toml");
+        let target_dir = selftest::target_dir(&dir);
+        let argv: Vec<&OsStr> = vec![
+            "cargo".as_ref(),
+            "gc".as_ref(),
+            "--manifest-path".as_ref(),
+            manifest.as_os_str(),
+            "--target-dir".as_ref(),
+            target_dir.as_os_str(),
+        ];
+        let existing = verify::fingerprint_dirs(&target_dir)?;
+        let CliOpts::Gc(gc_args) = CliOpts::from_iter_safe(argv)?;
+        let mut gc_config = Config::default()?;
+        configure(&mut gc_config, &gc_args)?;
+        let report = gc_once(&gc_config, &gc_args, true)?.context("GC is skipped")?;
+        ensure!(
+            !report.plan.is_empty(),
+            "Nothing is removed, though artifacts of the previous dependency version are stale",
+        );
+
+        // Leave a margin for coarse mtime granularity, as `--verify-after`.
+        let start = SystemTime::now() - Duration::from_secs(1);
+        for build in selftest::BUILDS {
+            selftest::run_cargo(config, &cargo, &dir, build)?;
+        }
+        let rebuilt = verify::rebuilt_units(&target_dir, start, &existing)?;
+        ensure!(
+            rebuilt.is_empty(),
+            "{} units are rebuilt after GC:{}",
+            rebuilt.len(),
+            list_paths(&rebuilt),
+        );
+        Ok(())
+    })();
+
+    if keep {
+        config
+            .shell()
+            .note(format_args!("The project is kept in {}", dir.display()))?;
+    } else {
+        fs::remove_dir_all(&dir)?;
+    }
+    let cargo_ver = get_cargo_version(&cargo)?;
+    match ret {
+        Ok(()) => config.shell().status(
+            "Passed",
+            format_args!("nothing is rebuilt after GC with cargo {}", cargo_ver),
+        )?,
+        Err(err) => {
+            return Err(err.context(format!(
+                "Self-test failed with cargo {}. Hashes of cargo-gc may not match your toolchain",
+                cargo_ver,
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn doctor(config: &Config, args: &CliArgs) -> Result<()> {
+    let ws = load_workspace(&root_manifest_path(args)?, config)?;
+    let target_dir = resolve_target_dir(config, args)?;
+    let mut findings = Vec::new();
+    for (profile, display_profile) in collect::profiles(&ws)? {
+        let dir = target_dir.join(&display_profile);
+        if dir.is_dir() {
+            findings.extend(doctor::check_profile(
+                &ws,
+                &profile,
+                &display_profile,
+                &dir,
+            )?);
+            findings.extend(doctor::check_rustc_versions(&display_profile, &dir)?);
+        }
+    }
+    for_each_profile(config, args, |display_profile, dir, reachable| {
+        let duplicates = dupes::find(dir, reachable)?;
+        findings.extend(doctor::check_duplicates(display_profile, &duplicates));
+        Ok(())
+    })?;
+    findings.extend(doctor::check_worktrees(ws.root(), &target_dir));
+
+    if findings.is_empty() {
+        println!("No problem found");
+    }
+    for finding in &findings {
+        print!("{}", finding);
+    }
+    Ok(())
+}
+
+/// Collect reachable units of the workspace for each existing host profile directory.
+fn for_each_profile(
+    config: &Config,
+    args: &CliArgs,
+    mut f: impl FnMut(&str, &Path, &collect::Reachable) -> Result<()>,
+) -> Result<()> {
+    let ws = load_workspace(&root_manifest_path(args)?, config)?;
+    let target_dir = resolve_target_dir(config, args)?;
+    for (profile, display_profile) in collect::profiles(&ws)? {
+        let dir = target_dir.join(&display_profile);
+        if !dir.is_dir() {
+            continue;
+        }
+        config.shell().status("Collecting", &display_profile)?;
+        let mut reachable = collect::Reachable::default();
+        collect::collect_workspace_units(
+            config,
+            &ws,
+            &[],
+            &profile,
+            &selection(args),
+            &mut reachable,
+        )?;
+        f(&display_profile, &dir, &reachable)?;
+    }
+    Ok(())
+}
+
+fn target_size(target_dir: &Path, jobs: usize) -> Result<u64> {
+    if !target_dir.exists() {
+        return Ok(0);
+    }
+    size::dir_size_par(target_dir, jobs)
+        .with_context(|| format!("Failed to get the size of {}", target_dir.display()))
+}
+
+fn resolve_target_dir(config: &Config, args: &CliArgs) -> Result<PathBuf> {
+    let target_dir = match &args.target_dir {
+        Some(target_dir) => config.cwd().join(target_dir),
+        None => load_workspace(&root_manifest_path(args)?, config)?
+            .target_dir()
+            .into_path_unlocked(),
+    };
+    Ok(remove::extended_path(&target_dir))
+}
+
+fn root_manifest_path(args: &CliArgs) -> Result<PathBuf> {
+    match &args.manifest_path {
+        Some(p) => Ok(p.clone()),
+        None => Ok(find_root_manifest_for_wd(&env::current_dir()?)?),
+    }
+}
+
+/// Run GC once.
+fn run(config: &Config, args: &CliArgs) -> Result<()> {
+    gc_once(config, args, false).map(drop)
+}
+
+/// Run GC once, and return the plan if `record_plan`, `--plan-file` or `--script` is set.
+/// Nothing is returned if skipped by `--min-interval`.
+fn gc_once(config: &Config, args: &CliArgs, record_plan: bool) -> Result<Option<interop::Report>> {
+    let record_plan = record_plan || args.plan_file.is_some() || args.script.is_some();
+    if args.register {
+        register_owner(config, args)?;
+        return Ok(None);
+    }
+    if args.compact && !compact::SUPPORTED {
+        bail!("`--compact` is only supported on Linux, where directories are swapped atomically");
+    }
+    if let Some(min_interval) = args.min_interval {
+        let stamp = resolve_target_dir(config, args)?.join(LAST_RUN_FILE);
+        if let Ok(elapsed) = stamp
+            .metadata()
+            .and_then(|m| m.modified())
+            .map(|t| t.elapsed())
+        {
+            if elapsed.map_or(true, |elapsed| elapsed < min_interval) {
+                config
+                    .shell()
+                    .status("Skipped", "last run is within `--min-interval`")?;
+                return Ok(None);
+            }
+        }
+    }
+
+    let start = Instant::now();
+    let size_before = if args.metrics_file.is_some() || record_plan {
+        target_size(&resolve_target_dir(config, args)?, jobs(args))?
+    } else {
+        0
+    };
+
+    let mut remover = remove::Remover::new(config, args.dry_run);
+    remover.keep_going = args.keep_going;
+    remover.defer_locked = args.defer_locked;
+    remover.why = args.why;
+    remover.throttle = args.throttle;
+    remover.jobs = jobs(args);
+    remover.no_size = no_size(args);
+    remover.deterministic = args.deterministic;
+    if !args.nextest.is_empty() {
+        let target_dir = resolve_target_dir(config, args)?;
+        for path in &args.nextest {
+            let binaries = nextest::binaries(&config.cwd().join(path), &target_dir)?;
+            remover
+                .protected
+                .extend(binaries.iter().map(|p| remove::normalize_path(p)));
+        }
+    }
+    if let Some(path) = &args.keep_list {
+        let target_dir = resolve_target_dir(config, args)?;
+        let keep = interop::read_keep_list(&config.cwd().join(path), &target_dir)?;
+        remover
+            .protected
+            .extend(keep.iter().map(|p| remove::normalize_path(p)));
+    }
+    if record_plan {
+        remover.plan = Some(Vec::new());
+    }
+    // Units built for the first time by the verification command are not rebuilds.
+    let existing_fingerprints = if args.verify_after.is_some()
+        && !args.dry_run
+        && (!args.no_manifest || args.target_dir.is_some())
+    {
+        verify::fingerprint_dirs(&resolve_target_dir(config, args)?)?
+    } else {
+        HashSet::new()
+    };
+    let target_dir = if args.no_manifest {
+        let target_dir = args
+            .target_dir
+            .as_ref()
+            .context("`--no-manifest` requires `--target-dir`")?;
+        let target_dir = remove::extended_path(&config.cwd().join(target_dir));
+        gc_on_disk(config, args, &target_dir, &mut remover)?;
+        target_dir
+    } else {
+        let target_dir = gc_manifest(config, args, &mut remover)?;
+        if args.fuzz {
+            gc_fuzz(config, args, &mut remover)?;
+        }
+        if args.include_registry {
+            gc_home_of_workspace(config, args, &mut remover)?;
+        }
+        target_dir
+    };
+    if args.coverage {
+        sweep::sweep_coverage(&target_dir, args.coverage_max_age, &mut remover)?;
+    }
+    if args.tmp {
+        sweep::sweep_tmp(&target_dir, &mut remover)?;
+    }
+    if args.legacy {
+        sweep::sweep_legacy(&target_dir, &mut remover)?;
+    }
+    for dir in &args.artifact_dirs {
+        let dir = config.cwd().join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+        let count = sweep::sweep_artifact_dir(&target_dir, &dir, &mut remover)?;
+        if count != 0 {
+            config.shell().status(
+                "Stale",
+                format_args!("{} copies of artifacts in {}", count, dir.display()),
+            )?;
+        }
+    }
+    if args.temps {
+        let (count, size) = sweep::sweep_temps(&target_dir, &mut remover)?;
+        config.shell().status(
+            "Temporaries",
+            format_args!(
+                "{} files of {} {}",
+                count,
+                ByteSize(size).to_string_as(true),
+                if args.dry_run {
+                    "can be removed"
+                } else {
+                    "removed"
+                },
+            ),
+        )?;
+    }
+    let lock_leftovers = if args.locks {
+        let cargo_home = config.home().as_path_unlocked();
+        sweep::sweep_locks(&target_dir, cargo_home, &mut remover)?
+    } else {
+        0
+    };
+    if args.sweep_unknown {
+        sweep::sweep_unknown(
+            config,
+            &target_dir,
+            &args.keep_globs,
+            args.yes,
+            &mut remover,
+        )?;
+    }
+    remover.join_background()?;
+    let pruned_dirs = if args.prune_empty_dirs {
+        sweep::prune_empty_dirs(&target_dir, &mut remover)?
+    } else {
+        0
+    };
+    let mut extra = String::new();
+    if pruned_dirs != 0 {
+        extra += &format!(", {} empty directories pruned", pruned_dirs);
+    }
+    if lock_leftovers != 0 {
+        extra += &format!(", {} leftovers of lock files removed", lock_leftovers);
+    }
+    report_finished(config, &remover, &extra)?;
+    if args.report_kept {
+        let kept = metrics::kept_sizes(&target_dir, &|p| remover.is_removed(p))?;
+        for (category, size) in kept {
+            config.shell().status(
+                "Remaining",
+                format_args!("{} in {}", ByteSize(size).to_string_as(true), category),
+            )?;
+        }
+    }
+
+    if let Some(path) = &args.metrics_file {
+        let mut removed = BTreeMap::new();
+        for path in remover.removed() {
+            *removed.entry(metrics::category(path)).or_insert(0) += 1;
+        }
+        let metrics = metrics::Metrics {
+            size_after: target_size(&target_dir, jobs(args))?,
+            target_dir: target_dir.clone(),
+            dry_run: args.dry_run,
+            size_before,
+            freed_bytes: remover.freed_bytes,
+            duration: start.elapsed(),
+            failures: remover.failures.len(),
+            removed,
+        };
+        metrics.write(&config.cwd().join(path), args.metrics_format)?;
+    }
 
-    let root_manifest_path = match &args.manifest_path {
-        Some(p) => p.clone(),
-        None => find_root_manifest_for_wd(&env::current_dir()?)?,
+    let report = if record_plan {
+        let mut keep = remover.protected.iter().cloned().collect::<Vec<_>>();
+        keep.sort();
+        Some(interop::Report {
+            dry_run: args.dry_run,
+            caches: vec![interop::Cache {
+                kind: "target-dir",
+                path: target_dir.clone(),
+                size: size_before,
+            }],
+            plan: remover.plan.take().unwrap_or_default(),
+            keep,
+        })
+    } else {
+        None
     };
-    let ws = Workspace::new(&root_manifest_path, &config)?;
+    if let (Some(path), Some(report)) = (&args.plan_file, &report) {
+        report.write(&config.cwd().join(path))?;
+    }
+    if let (Some(path), Some(report)) = (&args.script, &report) {
+        report.write_script(&config.cwd().join(path), args.script_format)?;
+    }
+
+    if let Some(command) = &args.verify_after {
+        if args.dry_run {
+            config
+                .shell()
+                .warn("Verification is skipped since nothing is removed in dry-run")?;
+        } else {
+            let cargo = cargo_exe(args).unwrap_or_else(|| "cargo".into());
+            let manifest = if args.no_manifest {
+                None
+            } else {
+                Some(root_manifest_path(args)?)
+            };
+            verify::verify_after(
+                config,
+                command.as_deref(),
+                &cargo,
+                manifest.as_deref(),
+                &target_dir,
+                &existing_fingerprints,
+            )?;
+        }
+    }
+
+    if !remover.skipped.is_empty() {
+        let skipped = std::mem::take(&mut remover.skipped);
+        match args.on_permission_denied {
+            OnPermissionDenied::Skip => {
+                config.shell().warn(format_args!(
+                    "{} paths are skipped due to insufficient permissions:{}",
+                    skipped.len(),
+                    list_paths(&skipped),
+                ))?;
+                let root_owned = privileged::root_owned(&skipped).len();
+                if root_owned != 0 {
+                    config.shell().note(format_args!(
+                        "{} of them are owned by root, probably built in containers of `cross`. \
+Pass `--on-permission-denied helper` to remove them by `sudo`, or \
+`--on-permission-denied container --container-image <IMAGE>` to remove them in a container",
+                        root_owned,
+                    ))?;
+                }
+            }
+            OnPermissionDenied::Helper => {
+                config.shell().status(
+                    "Removing",
+                    format_args!(
+                        "{} paths without permission by `{}`",
+                        skipped.len(),
+                        args.privileged_helper,
+                    ),
+                )?;
+                privileged::remove_with_helper(&args.privileged_helper, &skipped)?;
+            }
+            OnPermissionDenied::Container => {
+                let image = args
+                    .container_image
+                    .as_deref()
+                    .context("`--on-permission-denied container` requires `--container-image`")?;
+                config.shell().status(
+                    "Removing",
+                    format_args!("{} paths without permission in `{}`", skipped.len(), image),
+                )?;
+                privileged::remove_in_container(image, &target_dir, &skipped)?;
+            }
+        }
+    }
+
+    if !remover.deferred.is_empty() {
+        config.shell().warn(format_args!(
+            "{} locked files are scheduled for deletion on next reboot:{}",
+            remover.deferred.len(),
+            list_paths(&remover.deferred),
+        ))?;
+    }
+
+    if !remover.failures.is_empty() {
+        let errors = remover
+            .failures
+            .iter()
+            .map(|(p, err)| format!("\n  {}: {}", p.display(), err))
+            .collect::<String>();
+        config.shell().error(format_args!(
+            "{} errors occurred during GC:{}",
+            remover.failures.len(),
+            errors,
+        ))?;
+        return Err(PartialFailure.into());
+    }
+
+    if !args.dry_run && target_dir.exists() {
+        if args.deterministic {
+            let mtime = match env::var("SOURCE_DATE_EPOCH") {
+                Ok(epoch) => FileTime::from_unix_time(
+                    epoch.parse().context("Invalid `SOURCE_DATE_EPOCH`")?,
+                    0,
+                ),
+                Err(_) => FileTime::zero(),
+            };
+            remove::normalize_dir_mtimes(&target_dir, mtime)
+                .context("Failed to normalize modification times")?;
+        } else {
+            // Not recorded in deterministic mode since the time is not reproducible.
+            fs::write(target_dir.join(LAST_RUN_FILE), "")
+                .context("Failed to record the last run time")?;
+        }
+    }
+
+    Ok(report)
+}
+
+/// GC the target directory of the workspace of the manifest and
+/// return the target directory.
+fn gc_manifest(config: &Config, args: &CliArgs, remover: &mut remove::Remover) -> Result<PathBuf> {
+    let ws = load_workspace(&root_manifest_path(args)?, config)?;
+    let ws_target_dir = ws.target_dir().into_path_unlocked();
+
+    // Registered owners of the target directory, written only after safety checks pass.
+    let (owners, owners_changed) = updated_owners(&ws, &ws_target_dir)?;
+
+    // Workspaces in the same repository, possibly sharing the target directory.
+    let owner_set = owners.iter().cloned().collect::<BTreeSet<_>>();
+    let mut candidates = owner_set.clone();
+    if args.discover {
+        let repo_root = discover::repo_root(ws.root());
+        candidates.extend(discover::find_manifests(repo_root, &ws_target_dir));
+    }
+    let mut other_configs = Vec::new();
+    for manifest in candidates {
+        if manifest == ws.root_manifest() {
+            continue;
+        }
+        // Each workspace may have its own `.cargo/config.toml`.
+        let cwd = manifest.parent().unwrap().to_owned();
+        let mut other_config = Config::new(
+            Shell::new(),
+            cwd,
+            config.home().clone().into_path_unlocked(),
+        );
+        configure(&mut other_config, args)?;
+        other_configs.push((manifest, other_config));
+    }
+    let mut workspaces = vec![ws];
+    let mut unloaded_owners = Vec::new();
+    for (manifest, other_config) in &other_configs {
+        match Workspace::new(manifest, other_config) {
+            // Members are covered by their workspace root.
+            Ok(other) if other.root_manifest() != manifest.as_path() => {}
+            Ok(other)
+                if remove::normalize_path(&other.target_dir().into_path_unlocked())
+                    == remove::normalize_path(&ws_target_dir) =>
+            {
+                config
+                    .shell()
+                    .status("Discovered", other.root().display())?;
+                workspaces.push(other);
+            }
+            Ok(other) => {
+                config.shell().verbose(|s| {
+                    s.status(
+                        "Skipping",
+                        format_args!(
+                            "{}, which uses target directory {}",
+                            other.root().display(),
+                            other.target_dir().into_path_unlocked().display(),
+                        ),
+                    )
+                })?;
+            }
+            Err(err) => {
+                tracing::debug!("Skipping {}: {:?}", manifest.display(), err);
+                if owner_set.contains(manifest) {
+                    unloaded_owners.push(manifest.clone());
+                }
+            }
+        }
+    }
+
+    // Artifacts built by other toolchains have different hashes. Collect them as well.
+    let mut toolchain_configs = Vec::new();
+    for toolchain in &args.toolchains {
+        let env = toolchain::env(toolchain)?;
+        for ws in &workspaces {
+            let mut toolchain_config = Config::new(
+                Shell::new(),
+                ws.root().to_owned(),
+                config.home().clone().into_path_unlocked(),
+            );
+            toolchain_config.set_env(env.clone());
+            configure(&mut toolchain_config, args)?;
+            toolchain_configs.push((ws.root_manifest().to_owned(), toolchain_config));
+        }
+    }
+    let mut workspaces = workspaces;
+    for (manifest, toolchain_config) in &toolchain_configs {
+        workspaces.push(Workspace::new(manifest, toolchain_config)?);
+    }
+    let ws = &workspaces[0];
+
+    if !args.force && !args.allow_shared_target_dir && !unloaded_owners.is_empty() {
+        bail!(
+            "Target directory `{}` is shared with registered workspaces which fail to load, \
+whose artifacts would be removed:{}
+Fix or remove them, or use `--allow-shared-target-dir` to GC anyway.",
+            ws_target_dir.display(),
+            list_paths(&unloaded_owners),
+        );
+    }
+
     if !args.force
+        && !args.allow_external_target_dir
         && args.manifest_path.is_none()
-        && !ws.target_dir().into_path_unlocked().starts_with(ws.root())
+        && !ws_target_dir.starts_with(ws.root())
     {
-        eprintln!(
+        bail!(
             "\
 Target directory `{}` is outside the workspace `{}`
 cargo-gc is not suitable for target directory shared by difference workspaces.
-Use `-f` to force GC.",
+Use `--allow-external-target-dir` to GC anyway.",
             ws.target_dir().into_path_unlocked().display(),
             ws.root().display(),
         );
-        std::process::exit(1);
     }
 
-    let bytes = gc_workspace(&ws, args.dry_run)?;
-    let bytes_human = bytesize::ByteSize(bytes).to_string_as(true);
+    let target_dir = remove::extended_path(&ws_target_dir);
+    for ws in &workspaces {
+        ensure!(
+            load_pkg_lockfile(ws)?.is_some(),
+            "`{}` has no `Cargo.lock`, which is never generated by cargo-gc. \
+Run `cargo generate-lockfile` or build it first.",
+            ws.root_manifest().display(),
+        );
+    }
+    if args.no_network {
+        for ws in &workspaces {
+            let missing = collect::missing_sources(ws)?;
+            ensure!(
+                missing.is_empty(),
+                "Cannot collect `{}` without network. Packages missing from the local cache:{}",
+                ws.root_manifest().display(),
+                missing
+                    .iter()
+                    .map(|id| format!("\n  {}", id))
+                    .collect::<String>(),
+            );
+        }
+    }
+
+    if owners_changed && !args.dry_run {
+        fs::create_dir_all(&ws_target_dir)?;
+        discover::write_owners(&ws_target_dir, &owners)?;
+    }
+
+    gc_workspaces(&workspaces, args, &target_dir, remover)?;
+    if args.stale_doc {
+        // Workspaces of `--toolchain` are loaded with their own rustc.
+        let mut rustc_vvs = Vec::new();
+        for ws in &workspaces {
+            rustc_vvs.push(ws.config().load_global_rustc(Some(ws))?.verbose_version);
+        }
+        let count = sweep::sweep_stale_doc(&target_dir, &rustc_vvs, remover)?;
+        config.shell().status(
+            "Stale docs",
+            format_args!(
+                "{} directories of other toolchains {}",
+                count,
+                if args.dry_run {
+                    "can be removed"
+                } else {
+                    "removed"
+                },
+            ),
+        )?;
+    }
+    Ok(target_dir)
+}
+
+/// Registered owners of the target directory `target_dir` of `ws` with `ws` added and missing
+/// ones dropped, and whether they differ from the registered ones.
+fn updated_owners(ws: &Workspace, target_dir: &Path) -> Result<(Vec<PathBuf>, bool)> {
+    let mut owners = discover::read_owners(target_dir)?;
+    let owners_len = owners.len();
+    owners.retain(|p| p.is_file());
+    let has_missing = owners.len() != owners_len;
+    let registered = owners.iter().any(|p| p == ws.root_manifest());
+    if !registered {
+        owners.push(ws.root_manifest().to_owned());
+    }
+    Ok((owners, has_missing || !registered))
+}
+
+/// Register the workspace as an owner of its target directory without GC, by `--register`.
+fn register_owner(config: &Config, args: &CliArgs) -> Result<()> {
+    let ws = load_workspace(&root_manifest_path(args)?, config)?;
+    let target_dir = ws.target_dir().into_path_unlocked();
+    let (owners, changed) = updated_owners(&ws, &target_dir)?;
     if args.dry_run {
         config.shell().status(
-            "Finished",
-            format_args!("{} can be freed (dry-run)", bytes_human),
+            "Registering",
+            format_args!(
+                "owner of `{}` (dry-run, nothing is written)",
+                target_dir.display(),
+            ),
         )?;
-    } else {
-        config
-            .shell()
-            .status("Finished", format_args!("{} freed", bytes_human))?;
+        return Ok(());
+    }
+    if changed {
+        fs::create_dir_all(&target_dir)?;
+        discover::write_owners(&target_dir, &owners)?;
+    }
+    config.shell().status(
+        "Registered",
+        format_args!("owner of `{}`", target_dir.display()),
+    )?;
+    Ok(())
+}
+
+/// GC the target directory of `cargo fuzz`, whose units are built for the host triple with
+/// a nightly toolchain and RUSTFLAGS of sanitizers.
+fn gc_fuzz(config: &Config, args: &CliArgs, remover: &mut remove::Remover) -> Result<()> {
+    let root_manifest = root_manifest_path(args)?;
+    let manifest = match fuzz::manifest(root_manifest.parent().unwrap()) {
+        Some(manifest) => manifest,
+        None => {
+            config
+                .shell()
+                .warn("No fuzz crate found, skipping `--fuzz`")?;
+            return Ok(());
+        }
+    };
+    let fuzz_root = manifest.parent().unwrap();
+    let toolchain_env = toolchain::env(&args.fuzz_toolchain)?;
+    let new_config = |env: HashMap<String, String>| -> Result<Config> {
+        let mut fuzz_config = Config::new(
+            Shell::new(),
+            fuzz_root.to_owned(),
+            config.home().clone().into_path_unlocked(),
+        );
+        fuzz_config.set_env(env);
+        // `--target-dir` is for the main workspace.
+        configure_with_target_dir(&mut fuzz_config, args, &None)?;
+        Ok(fuzz_config)
+    };
+
+    let fuzz_config = new_config(toolchain_env.clone())?;
+    let ws = Workspace::new(&manifest, &fuzz_config)?;
+    ensure!(
+        load_pkg_lockfile(&ws)?.is_some(),
+        "`{}` has no `Cargo.lock`. Run `cargo fuzz build` first.",
+        manifest.display(),
+    );
+    let target_dir = remove::extended_path(&ws.target_dir().into_path_unlocked());
+    if !target_dir.is_dir() {
+        return Ok(());
+    }
+
+    let profiles = collect::profiles(&ws)?;
+    let mut entries = fs::read_dir(&target_dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let triple = match entry.file_name().into_string() {
+            // `cargo fuzz` always passes `--target`.
+            Ok(name) if name.contains('-') => name,
+            _ => continue,
+        };
+        for (profile, display_profile) in &profiles {
+            let dir = entry.path().join(display_profile);
+            if !dir.is_dir() || remover.is_removed(&dir) {
+                continue;
+            }
+            let mut env = toolchain_env.clone();
+            if let Some(flags) = fuzz::rustflags(&dir)? {
+                env.insert("RUSTFLAGS".into(), flags);
+            }
+            let fuzz_config = new_config(env)?;
+            let ws = Workspace::new(&manifest, &fuzz_config)?;
+            gc_artifects(
+                std::slice::from_ref(&ws),
+                args,
+                &Some(triple.clone()),
+                profile,
+                display_profile,
+                &dir,
+                remover,
+            )?;
+        }
     }
+    Ok(())
+}
 
+/// GC the target directory using only on-disk information.
+fn gc_on_disk(
+    config: &Config,
+    args: &CliArgs,
+    target_dir: &Path,
+    remover: &mut remove::Remover,
+) -> Result<()> {
+    if let Some(max_age) = args.prune_unused_profiles {
+        sweep::prune_unused_profiles(config, target_dir, max_age, remover)?;
+    }
+    for dir in ondisk::profile_dirs(target_dir)? {
+        if remover.is_removed(&dir) {
+            continue;
+        }
+        let display_dir = dir.strip_prefix(target_dir).unwrap_or(&dir);
+        config.shell().status("Collecting", display_dir.display())?;
+        let mut reachable = ondisk::collect(&dir)?;
+        if let Some(count) = args.keep_latest {
+            reachable.keep_latest(&dir, count)?;
+        }
+        if let Some(count) = args.keep_uplifted {
+            reachable.keep_uplifted(&dir, count)?;
+        }
+        reachable.preserve_uplifted = preserve_uplifted(config, args)?;
+        reachable.post_process_extensions = post_process_extensions(config, args, None)?;
+        reachable.aggressive_rmeta = args.aggressive_rmeta;
+        if !args.crates.is_empty() {
+            reachable.restrict(ondisk::package_hashes(&dir, &args.crates)?);
+        }
+        if args.host_only || args.no_host {
+            // Without the graph, dependencies of build scripts and proc-macros count as target.
+            let host_only = args.host_only;
+            reachable.restrict(ondisk::hashes_by_kind(&dir, |_, is_host_build| {
+                is_host_build == host_only
+            })?);
+        }
+        tracing::trace!("Reachable: {:?}", reachable);
+        remove_unreachable(&dir, &reachable, remover)?;
+    }
     Ok(())
 }
 
+fn selection(args: &CliArgs) -> collect::Selection {
+    collect::Selection {
+        all_members: args.workspace,
+        modes: args.modes.clone(),
+        features: args.features.clone(),
+        no_default_features: args.no_default_features,
+        honor_rust_version: !args.ignore_rust_version,
+    }
+}
+
+fn configure(config: &mut Config, args: &CliArgs) -> CargoResult<()> {
+    configure_with_target_dir(config, args, &args.target_dir)
+}
+
+fn configure_with_target_dir(
+    config: &mut Config,
+    args: &CliArgs,
+    target_dir: &Option<PathBuf>,
+) -> CargoResult<()> {
+    config.configure(
+        args.verbose,
+        args.quiet,
+        args.color.as_deref(),
+        args.frozen || args.no_network,
+        // Never update `Cargo.lock` of workspaces. Cargo fails if it is outdated.
+        true,
+        args.offline,
+        target_dir,
+        &[],
+        &[],
+    )
+}
+
+fn jobs(args: &CliArgs) -> usize {
+    match args.fs_profile {
+        FsProfile::Local => args.jobs,
+        FsProfile::Network => args.jobs.max(NETWORK_JOBS),
+    }
+}
+
+fn no_size(args: &CliArgs) -> bool {
+    args.no_size || args.fs_profile == FsProfile::Network
+}
+
+/// Whether to never touch uplifted files, by `--preserve-uplifted` or `gc.preserve-uplifted`
+/// in cargo configurations.
+fn preserve_uplifted(config: &Config, args: &CliArgs) -> CargoResult<bool> {
+    Ok(args.preserve_uplifted || config.get::<Option<bool>>("gc.preserve-uplifted")? == Some(true))
+}
+
+/// Extensions of post-processed outputs kept with uplifted binaries, by
+/// `gc.target.<triple>.post-process-extensions` or `gc.post-process-extensions` in cargo
+/// configurations, or the built-in list, and `--post-process-ext`.
+fn post_process_extensions(
+    config: &Config,
+    args: &CliArgs,
+    triple: Option<&str>,
+) -> CargoResult<Vec<String>> {
+    let mut exts = match triple {
+        Some(triple) => config
+            .get::<Option<Vec<String>>>(
+                &format!("gc.target.{}.post-process-extensions", triple,),
+            )?,
+        None => None,
+    };
+    if exts.is_none() {
+        exts = config.get("gc.post-process-extensions")?;
+    }
+    let mut exts = exts.unwrap_or_else(|| {
+        collect::POST_PROCESS_EXTENSIONS
+            .iter()
+            .map(|ext| ext.to_string())
+            .collect()
+    });
+    exts.extend(args.post_process_exts.iter().cloned());
+    Ok(exts)
+}
+
+fn list_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|p| format!("\n  {}", p.display()))
+        .collect()
+}
+
+/// Load the workspace of `manifest`, with hints if its edition is unknown to the cargo library
+/// cargo-gc is built with, like editions after 2018.
+fn load_workspace<'cfg>(manifest: &Path, config: &'cfg Config) -> Result<Workspace<'cfg>> {
+    Workspace::new(manifest, config).map_err(|err| {
+        if err
+            .chain()
+            .any(|cause| cause.to_string().contains("edition"))
+        {
+            err.context(format!(
+                "The edition of {} is unsupported by the cargo library of cargo-gc ({}). \
+Pass `--no-manifest --target-dir <DIR>` to GC by on-disk information only",
+                manifest.display(),
+                cargo::version(),
+            ))
+        } else {
+            err
+        }
+    })
+}
+
 fn get_cargo_version(cargo_exe: &OsStr) -> Result<Version> {
     let output = std::process::Command::new(&cargo_exe)
         .arg("--version")
@@ -127,65 +2170,178 @@ fn get_cargo_version(cargo_exe: &OsStr) -> Result<Version> {
     Ok(Version::parse(version)?)
 }
 
-fn assert_cargo_version() -> Result<()> {
-    let cargo_exe = std::env::var_os("CARGO").context(
-        "Missing environment `CARGO`. Please run as `cargo gc` instead of the executable itself.",
+/// Inclusive ranges of cargo `(major, minor)` versions whose metadata hashing and target
+/// directory layout match the cargo library cargo-gc is built with.
+/// Patch releases never change them.
+const COMPATIBLE_CARGO: &[((u64, u64), (u64, u64))] = &[((1, 51), (1, 51))];
+
+/// The cargo executable given by `--cargo`, or `$CARGO` set when running as `cargo gc`.
+fn cargo_exe(args: &CliArgs) -> Option<OsString> {
+    match &args.cargo {
+        Some(path) => Some(path.clone().into_os_string()),
+        None => env::var_os("CARGO"),
+    }
+}
+
+fn assert_cargo_version(args: &CliArgs) -> Result<()> {
+    let cargo_exe = cargo_exe(args).context(
+        "Missing environment `CARGO`. Please run as `cargo gc` instead of the executable itself, \
+or specify `--cargo`.",
     )?;
     let cargo_ver = get_cargo_version(&cargo_exe)?;
     let libcargo_ver = {
         let v = cargo::version();
         Version::new(v.major.into(), v.minor.into(), v.patch.into())
     };
-    if cargo_ver < libcargo_ver {
-        eprintln!(
-            "Your cargo ({}) is older than the library used by cargo-gc ({}).
-In-use artifacts may suspiciously be removed due to cargo internal change.
-To do a garbage collection anyway, specify `-f`.",
-            cargo_ver, libcargo_ver,
+    let compatible = COMPATIBLE_CARGO.iter().any(|&(min, max)| {
+        let v = (cargo_ver.major, cargo_ver.minor);
+        min <= v && v <= max
+    });
+    if !compatible {
+        let ranges = COMPATIBLE_CARGO
+            .iter()
+            .map(|&((min_major, min_minor), (max_major, max_minor))| {
+                if (min_major, min_minor) == (max_major, max_minor) {
+                    format!("{}.{}.x", min_major, min_minor)
+                } else {
+                    format!(
+                        "{}.{}.x to {}.{}.x",
+                        min_major, min_minor, max_major, max_minor,
+                    )
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let relation = if cargo_ver < libcargo_ver {
+            "older than"
+        } else {
+            "newer than"
+        };
+        bail!(
+            "Your cargo ({}) is {} versions compatible with the library used by cargo-gc ({}): {}.
+Artifact hashes or the target layout may differ and in-use artifacts may be removed.
+Use a compatible toolchain, eg. `cargo +{} gc`.
+To do a garbage collection anyway, specify `--allow-version-mismatch`.",
+            cargo_ver,
+            relation,
+            libcargo_ver,
+            ranges,
+            libcargo_ver,
         );
-        std::process::exit(1);
     }
     Ok(())
 }
 
-fn gc_workspace(ws: &Workspace, dry_run: bool) -> CargoResult<u64> {
-    let target_dir = ws.target_dir().into_path_unlocked();
-    let mut collected_bytes = 0u64;
+fn gc_workspaces(
+    workspaces: &[Workspace],
+    args: &CliArgs,
+    target_dir: &Path,
+    remover: &mut remove::Remover,
+) -> CargoResult<()> {
+    if args.bench_aware {
+        remover.protected.insert(target_dir.join("criterion"));
+    }
 
-    let mut check = |target: &Option<String>, dir: &Path| -> CargoResult<()> {
-        let p = dir.join("debug");
-        if p.is_dir() {
-            collected_bytes += gc_artifects(ws, target, "dev", "debug", &p, dry_run)?;
-        }
-        let p = dir.join("release");
-        if p.is_dir() {
-            collected_bytes += gc_artifects(ws, target, "release", "release", &p, dry_run)?;
+    let mut profiles = Vec::new();
+    let mut trim_paths_dirs = BTreeSet::new();
+    for ws in workspaces {
+        for profile in collect::profiles(ws)? {
+            if !profiles.contains(&profile) {
+                profiles.push(profile);
+            }
         }
-        Ok(())
-    };
+        trim_paths_dirs.extend(collect::trim_paths_dirs(ws)?);
+    }
+    let config = workspaces[0].config();
 
-    check(&None, &target_dir)?;
-    for entry in fs::read_dir(target_dir)? {
-        let entry = entry?;
+    // The host directory and directories of target triples.
+    let mut triple_dirs = vec![(None, target_dir.to_owned())];
+    let mut entries = fs::read_dir(target_dir)?.collect::<io::Result<Vec<_>>>()?;
+    if args.deterministic {
+        entries.sort_by_key(|entry| entry.file_name());
+    }
+    for entry in entries {
         if let Some(file_name) = entry.file_name().to_str() {
             // A rough but easy way to detect target triples like `x86_64-unknown-linux-gnu`.
             if file_name.contains('-') {
-                check(&Some(file_name.to_owned()), &entry.path())?;
+                triple_dirs.push((Some(file_name.to_owned()), entry.path()));
+            }
+        }
+    }
+
+    // Collect and check all profiles before removing or recording anything.
+    let mut collected = Vec::new();
+    for (target, triple_dir) in &triple_dirs {
+        for (profile, display_profile) in &profiles {
+            let dir = triple_dir.join(display_profile);
+            if !dir.is_dir() {
+                continue;
+            }
+            if trim_paths_dirs.contains(display_profile) {
+                config.shell().note(format_args!(
+                    "Keeping {}, since hashes with `trim-paths` are not supported",
+                    dir.display(),
+                ))?;
+                continue;
+            }
+            let reachable =
+                collect_profile(workspaces, args, target, profile, display_profile, remover)?;
+            collected.push((target, profile, display_profile, dir, reachable));
+        }
+    }
+    if args.check {
+        let mut problems = String::new();
+        for (_, _, _, dir, reachable) in &collected {
+            for problem in reachable.verify(dir)? {
+                problems += &format!("\n  {}: {}", dir.display(), problem);
             }
         }
+        if !problems.is_empty() {
+            bail!(
+                "Reachable artifacts look inconsistent with the computed hashes, \
+probably due to cargo internal changes. Nothing is removed.{}",
+                problems,
+            );
+        }
+    }
+
+    if let Some(max_age) = args.prune_unused_profiles {
+        sweep::prune_unused_profiles(config, target_dir, max_age, remover)?;
+    }
+    for (target, profile, display_profile, dir, reachable) in collected {
+        if !remover.is_removed(&dir) {
+            gc_artifects(
+                workspaces,
+                args,
+                target,
+                profile,
+                display_profile,
+                &dir,
+                reachable,
+                remover,
+            )?;
+        }
     }
 
-    Ok(collected_bytes)
+    Ok(())
 }
 
-fn gc_artifects(
-    ws: &Workspace,
+/// Collect reachable units of `profile`, without removing anything.
+fn collect_profile(
+    workspaces: &[Workspace],
+    args: &CliArgs,
     target: &Option<String>,
     profile: &str,
     display_profile: &str,
-    dir: &Path,
-    dry_run: bool,
-) -> CargoResult<u64> {
+    remover: &mut remove::Remover,
+) -> CargoResult<collect::Reachable> {
+    let _span = tracing::info_span!(
+        "collect",
+        triple = target.as_deref().unwrap_or("host"),
+        profile = display_profile,
+    )
+    .entered();
+    let ws = &workspaces[0];
     let targets = match target {
         Some(target) => {
             ws.config()
@@ -200,72 +2356,399 @@ fn gc_artifects(
     };
 
     let mut reachable = collect::Reachable::default();
-    collect::collect_workspace_units(ws.config(), &ws, &targets, profile, &mut reachable)?;
-    log::trace!("Reachable: {:?}", reachable);
+    for ws in workspaces {
+        // Custom profiles may be defined by only some workspaces sharing the directory.
+        if !collect::profiles(ws)?.iter().any(|(p, _)| p == profile) {
+            continue;
+        }
+        collect::collect_workspace_units(
+            ws.config(),
+            ws,
+            &targets,
+            profile,
+            &selection(args),
+            &mut reachable,
+        )?;
+    }
+    tracing::trace!("Reachable: {:?}", reachable);
 
-    let mut collected_bytes = 0u64;
-    let mut remove = |path: &Path| -> Result<()> {
+    if !reachable.external.is_empty() {
         ws.config().shell().verbose(|s| {
-            if dry_run {
-                s.status("Removing", format_args!("(skipped) {}", path.display()))
+            s.status(
+                "Keeping",
+                format_args!(
+                    "path dependencies outside the workspace:{}",
+                    reachable
+                        .external
+                        .iter()
+                        .map(|id| format!("\n  {}", id))
+                        .collect::<String>(),
+                ),
+            )
+        })?;
+    }
+
+    remover.protected.extend(
+        reachable
+            .doctests
+            .iter()
+            .map(|p| remove::extended_path(&remove::normalize_path(p))),
+    );
+    Ok(reachable)
+}
+
+/// GC the profile directory `dir` with units `reachable` collected by `collect_profile`.
+#[allow(clippy::too_many_arguments)]
+fn gc_artifects(
+    workspaces: &[Workspace],
+    args: &CliArgs,
+    target: &Option<String>,
+    profile: &str,
+    display_profile: &str,
+    dir: &Path,
+    mut reachable: collect::Reachable,
+    remover: &mut remove::Remover,
+) -> CargoResult<()> {
+    let _span = tracing::info_span!(
+        "gc",
+        triple = target.as_deref().unwrap_or("host"),
+        profile = display_profile,
+    )
+    .entered();
+    let ws = &workspaces[0];
+
+    // Artifacts of removed or renamed packages are unreachable. Identify them before retention,
+    // which may still keep them, eg. for other branches whose lock files have them.
+    let mut orphans = BTreeMap::new();
+    if args.orphans {
+        if let Some(packages) = collect::locked_packages(workspaces)? {
+            orphans = ondisk::orphans(dir, &packages)?;
+            for hashes in orphans.values() {
+                reachable.orphans.extend(hashes.iter().cloned());
+            }
+        }
+    }
+
+    // Builds of the current branch, taken before retention and recorded after removal.
+    let mut branch_record = None;
+    let mut stale_records = BTreeSet::new();
+    if args.branch_retention.is_some() || args.deleted_branches {
+        let branch = branches::current_branch(ws.root());
+        if let (Some(branch), false) = (&branch, args.dry_run) {
+            branch_record = Some((branch.clone(), reachable.fingerprints.clone()));
+        }
+        if args.deleted_branches {
+            let hashes = branches::deleted_only(dir, ws.root(), &reachable, &mut stale_records)?;
+            reachable.restrict(hashes);
+        }
+        if let Some(max_age) = args.branch_retention {
+            let kept = branches::keep_active(
+                dir,
+                ws.root(),
+                branch.as_deref(),
+                max_age,
+                &mut reachable,
+                &mut stale_records,
+            )?;
+            if !kept.is_empty() {
+                ws.config().shell().verbose(|s| {
+                    s.status(
+                        "Keeping",
+                        format_args!("artifacts of branches: {}", kept.join(", ")),
+                    )
+                })?;
+            }
+        }
+    }
+
+    if !args.crates.is_empty() {
+        reachable.restrict(ondisk::package_hashes(dir, &args.crates)?);
+    }
+    if args.host_only || args.no_host {
+        let (host_pkgs, target_pkgs) = reachable.host_and_target_packages();
+        // Packages unknown to the graph, eg. removed ones, are left untouched.
+        let hashes = ondisk::hashes_by_kind(dir, |pkg, is_host_build| {
+            if args.host_only {
+                is_host_build || host_pkgs.contains(pkg)
             } else {
-                s.status("Removing", path.display())
+                !is_host_build && target_pkgs.contains(pkg)
             }
         })?;
-        collected_bytes += remove_recursive(&path, dry_run)?;
-        Ok(())
-    };
+        reachable.restrict(hashes);
+    }
+
+    if args.bench_aware {
+        reachable.keep_previous_benches(dir)?;
+    }
+    if let Some(count) = args.keep_latest {
+        reachable.keep_latest(dir, count)?;
+    }
+    if let Some(count) = args.keep_uplifted {
+        reachable.keep_uplifted(dir, count)?;
+    }
+    reachable.preserve_uplifted = preserve_uplifted(ws.config(), args)?;
+    reachable.post_process_extensions =
+        post_process_extensions(ws.config(), args, target.as_deref())?;
+    reachable.aggressive_rmeta = args.aggressive_rmeta;
+
+    // Builds retained above are kept, even if their packages are orphans.
+    if !orphans.is_empty() {
+        let removed = orphans
+            .iter()
+            .filter(|(_, hashes)| {
+                hashes.iter().any(|hash| {
+                    let in_scope = reachable.scope.as_ref().map_or(true, |s| s.contains(hash));
+                    in_scope && !reachable.keeps_hash(hash)
+                })
+            })
+            .map(|(pkg, _)| pkg.clone())
+            .collect::<Vec<_>>();
+        if !removed.is_empty() {
+            ws.config().shell().status(
+                "Orphaned",
+                format_args!(
+                    "{} packages no longer in the lock file: {}",
+                    removed.len(),
+                    removed.join(", "),
+                ),
+            )?;
+        }
+    }
+
+    let mut compacted = false;
+    if args.compact && !args.dry_run {
+        ws.config().shell().status("Compacting", dir.display())?;
+        let mut plan = remove::Remover::new(ws.config(), true);
+        plan.no_size = remover.no_size;
+        plan.protected = remover.protected.clone();
+        plan.plan = remover.plan.as_ref().map(|_| Vec::new());
+        remove_unreachable(dir, &reachable, &mut plan)?;
+        let removed = plan.removed().map(Path::to_owned).collect();
+        match compact::rebuild(dir, &removed, &remover.protected)? {
+            Some(old_dir) => {
+                remover.absorb(plan);
+                remover.remove_in_background(old_dir);
+                compacted = true;
+            }
+            None => ws.config().shell().warn(format_args!(
+                "{} is locked by a running build, removing in place instead",
+                dir.display(),
+            ))?,
+        }
+    }
+    if !compacted {
+        tracing::info_span!("remove").in_scope(|| remove_unreachable(dir, &reachable, remover))?;
+    }
+    if let Some((branch, names)) = branch_record {
+        branches::record(dir, &branch, &names)?;
+    }
+    if !args.dry_run {
+        // Only after their builds are removed, so a failed run retries them.
+        for path in stale_records {
+            fs::remove_file(path)?;
+        }
+    }
+
+    if args.prune_dirty {
+        // Fingerprints are relative to the target directory, not the triple directory.
+        let target_root = match target {
+            Some(_) => dir.parent().and_then(Path::parent),
+            None => dir.parent(),
+        };
+        let dirty = target_root.map_or_else(Vec::new, |target_root| {
+            dirty::dirty_units(&reachable, dir, target_root)
+        });
+        for idx in dirty {
+            for file in &reachable.graph[idx].deps_files {
+                let path = dir.join("deps").join(file);
+                if path.exists() && !remover.is_removed(&path) {
+                    remover.remove(&path, "reachable but dirty, rebuilt by cargo anyway")?;
+                }
+            }
+        }
+    }
+
+    if let Some(max_age) = args.strip_debuginfo {
+        strip::strip_old(ws.config(), dir, max_age, remover)?;
+    }
 
-    let subdirs = &[
-        (".fingerprint", &reachable.fingerprints),
-        ("build", &reachable.builds),
-        ("deps", &reachable.deps),
+    if args.report_kept {
+        // Counted on disk, since retention options keep more than reachable units.
+        let count_kept = |subdir: &str| -> io::Result<usize> {
+            let names = ondisk::list_names(&dir.join(subdir))?;
+            Ok(names
+                .iter()
+                .filter(|name| !remover.is_removed(&dir.join(subdir).join(name)))
+                .count())
+        };
+        ws.config().shell().status(
+            "Kept",
+            format_args!(
+                "{} units with {} artifacts in `deps` of {}",
+                count_kept(".fingerprint")?,
+                count_kept("deps")?,
+                match target {
+                    Some(target) => format!("{}/{}", target, display_profile),
+                    None => display_profile.to_owned(),
+                },
+            ),
+        )?;
+    }
+    Ok(())
+}
+
+/// Remove unreachable artifacts in the profile directory `dir`.
+fn remove_unreachable(
+    dir: &Path,
+    reachable: &collect::Reachable,
+    remover: &mut remove::Remover,
+) -> Result<()> {
+    let subdirs: &[(&str, &dyn Fn(&str) -> bool)] = &[
+        (".fingerprint", &|name: &str| {
+            reachable.fingerprints.contains(name)
+        }),
+        ("build", &|name: &str| reachable.builds.contains(name)),
+        ("deps", &|name: &str| reachable.keeps_dep(name)),
     ];
-    for &(subdir, set) in subdirs {
-        for entry in fs::read_dir(dir.join(subdir))? {
-            let entry = entry?;
-            if entry
-                .file_name()
-                .to_str()
-                .map_or(true, |name| !set.contains(name))
+    for &(subdir, keeps) in subdirs {
+        for entry in remover.read_dir(&dir.join(subdir))? {
+            match entry.file_name().to_str() {
+                Some(name) if keeps(name) || !reachable.in_scope(name) => {}
+                Some(name) => remover.remove(&entry.path(), reachable.removal_reason(name))?,
+                None => remover.remove(&entry.path(), "non-UTF-8 name")?,
+            }
+        }
+    }
+
+    // Collect outputs of build script runs in directories only used for compilation,
+    // and vice versa.
+    // https://github.com/rust-lang/cargo/blob/0a4ec2917698ee067b257b580698d7ffb8ccbe2f/src/cargo/core/compiler/custom_build.rs
+    const RUN_OUTPUTS: &[&str] = &["out", "output", "root-output", "stderr"];
+    for name in &reachable.builds {
+        let is_compile = reachable.build_compiles.contains(name);
+        let is_run = reachable.build_runs.contains(name);
+        if is_compile == is_run || !reachable.in_scope(name) {
+            continue;
+        }
+        let build_dir = dir.join("build").join(name);
+        if !build_dir.is_dir() {
+            continue;
+        }
+        for entry in remover.read_dir(&build_dir)? {
+            let file_name = entry.file_name();
+            let file_name = file_name.to_string_lossy();
+            let is_run_output = RUN_OUTPUTS.contains(&&*file_name);
+            let is_compile_output =
+                file_name.starts_with("build-script-") || file_name.starts_with("build_script_");
+            if is_compile && is_run_output {
+                remover.remove(
+                    &entry.path(),
+                    "run output in a directory only used for compilation",
+                )?;
+            } else if is_run && is_compile_output {
+                remover.remove(
+                    &entry.path(),
+                    "build script in a directory only used for running",
+                )?;
+            }
+        }
+    }
+
+    // Libraries built in build mode emit both `.rlib` and `.rmeta` for pipelining. Units of
+    // `cargo check` only emit `.rmeta`, so they are never affected.
+    if reachable.aggressive_rmeta {
+        for entry in remover.read_dir(&dir.join("deps"))? {
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.ends_with(".rmeta")
+                && reachable.in_scope(&name)
+                && !remover.is_removed(&path)
+                && path.with_extension("rlib").is_file()
+                && !remover.is_removed(&path.with_extension("rlib"))
             {
-                remove(&entry.path())?;
+                remover.remove(&path, "metadata of a library with its rlib")?;
             }
         }
     }
 
-    // Collect uplifted binaries.
-    for entry in fs::read_dir(dir)? {
-        let entry = entry?;
+    if reachable.scope.is_some() || reachable.preserve_uplifted {
+        return Ok(());
+    }
+
+    // Collect uplifted binaries and their dSYM bundles.
+    for entry in remover.read_dir(dir)? {
         let file_name = entry.file_name();
-        // Exclude directory and `.cargo-lock`.
-        if entry.file_type()?.is_file()
+        let is_dsym = file_name
+            .to_str()
+            .map_or(false, |name| name.ends_with(".dSYM"));
+        let is_candidate = match entry.file_type() {
+            Ok(ty) => ty.is_file() || ty.is_dir() && is_dsym,
+            Err(err) => {
+                remover.tolerate(&entry.path(), err)?;
+                continue;
+            }
+        };
+        // Exclude other directories and `.cargo-lock`.
+        if is_candidate
             && file_name != OsStr::new(".cargo-lock")
             && file_name
                 .to_str()
-                .map_or(true, |name| !reachable.uplifts.contains(name))
+                .map_or(true, |name| !reachable.keeps_uplift(name))
         {
-            remove(&entry.path())?;
+            remover.remove(&entry.path(), "not uplifted by any reachable unit")?;
         }
     }
 
-    Ok(collected_bytes)
+    Ok(())
 }
 
-fn remove_recursive(path: &Path, dry_run: bool) -> Result<u64> {
-    let meta = path.symlink_metadata()?;
-    let mut ret = meta.len();
-    if meta.is_dir() {
-        for entry in fs::read_dir(path)? {
-            ret += remove_recursive(&entry?.path(), dry_run)?;
-        }
-        if !dry_run {
-            fs::remove_dir(path)?;
-        }
-    } else {
-        if !dry_run {
-            fs::remove_file(path)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_all_profiles_before_removal() {
+        let dir = env::temp_dir().join(format!("cargo-gc-check-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        selftest::create_project(&dir).unwrap();
+        let config = Config::default().unwrap();
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        selftest::run_cargo(&config, &cargo, &dir, &["build"]).unwrap();
+        selftest::run_cargo(&config, &cargo, &dir, &["build", "--release"]).unwrap();
+
+        // Unreachable artifacts in the first profile, and fingerprints of other hashes than
+        // computed ones in the second profile.
+        let target_dir = selftest::target_dir(&dir);
+        let stale = target_dir.join("debug/deps/libstale-0123456789abcdef.rlib");
+        fs::write(&stale, "").unwrap();
+        let fingerprint_dir = target_dir.join("release/.fingerprint");
+        for (i, entry) in fs::read_dir(&fingerprint_dir).unwrap().enumerate() {
+            let path = entry.unwrap().path();
+            let name = path.file_name().unwrap().to_str().unwrap();
+            let pkg = &name[..name.rfind('-').unwrap()];
+            let renamed = fingerprint_dir.join(format!("{}-{:016x}", pkg, i));
+            fs::rename(&path, renamed).unwrap();
         }
+
+        let manifest = dir.join("Cargo.toml");
+        let argv: Vec<&OsStr> = vec![
+            "cargo".as_ref(),
+            "gc".as_ref(),
+            "--manifest-path".as_ref(),
+            manifest.as_os_str(),
+            "--target-dir".as_ref(),
+            target_dir.as_os_str(),
+            "--check".as_ref(),
+        ];
+        let CliOpts::Gc(args) = CliOpts::from_iter_safe(argv).unwrap();
+        let mut gc_config = Config::default().unwrap();
+        configure(&mut gc_config, &args).unwrap();
+        let err = gc_once(&gc_config, &args, false).unwrap_err();
+        assert!(err.to_string().contains("release"), "{:#}", err);
+        assert!(stale.exists());
+        assert!(target_dir.join("debug/.fingerprint").is_dir());
+
+        fs::remove_dir_all(&dir).unwrap();
     }
-    Ok(ret)
 }
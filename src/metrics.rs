@@ -0,0 +1,212 @@
+//! Machine-readable metrics of a GC run.
+use anyhow::{Context as _, Result};
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    io::Write as _,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use structopt::clap::arg_enum;
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum Format {
+        Prometheus,
+        Json,
+    }
+}
+
+#[derive(Debug)]
+pub struct Metrics {
+    pub target_dir: PathBuf,
+    pub dry_run: bool,
+    pub size_before: u64,
+    pub size_after: u64,
+    pub freed_bytes: u64,
+    pub duration: Duration,
+    pub failures: usize,
+    /// Count of removed entries by category.
+    pub removed: BTreeMap<&'static str, u64>,
+}
+
+/// Category of a removed path, by its name for legacy outputs, otherwise by the directory
+/// containing it.
+pub fn category(path: &Path) -> &'static str {
+    match path.file_name().and_then(|p| p.to_str()) {
+        Some("rls") | Some("save-analysis") => return "legacy",
+        Some(".cargo-lock.tmp") | Some(".package-cache.tmp") => return "lock",
+        _ => {}
+    }
+    let parent = path.parent().and_then(|p| p.file_name());
+    match parent.and_then(|p| p.to_str()) {
+        Some(".fingerprint") => "fingerprint",
+        Some("build") => "build",
+        Some("deps") => "deps",
+        _ => "other",
+    }
+}
+
+/// Sizes remaining under `path` by category, excluding paths `is_removed`, which may still
+/// exist in dry-run. Entries in categorized directories are accounted with all their
+/// contents.
+pub fn kept_sizes(
+    path: &Path,
+    is_removed: &dyn Fn(&Path) -> bool,
+) -> io::Result<BTreeMap<&'static str, u64>> {
+    fn walk(
+        path: &Path,
+        inherited: Option<&'static str>,
+        is_removed: &dyn Fn(&Path) -> bool,
+        sizes: &mut BTreeMap<&'static str, u64>,
+    ) -> io::Result<()> {
+        if is_removed(path) {
+            return Ok(());
+        }
+        let meta = path.symlink_metadata()?;
+        let fixed = inherited.or_else(|| Some(category(path)).filter(|&c| c != "other"));
+        *sizes.entry(fixed.unwrap_or("other")).or_insert(0) += meta.len();
+        if meta.is_dir() {
+            for entry in fs::read_dir(path)? {
+                walk(&entry?.path(), fixed, is_removed, sizes)?;
+            }
+        }
+        Ok(())
+    }
+
+    let mut sizes = BTreeMap::new();
+    if path.exists() {
+        walk(path, None, is_removed, &mut sizes)?;
+    }
+    Ok(sizes)
+}
+
+impl Metrics {
+    /// Write metrics to `path`. The Prometheus textfile is replaced atomically,
+    /// while JSON lines are appended.
+    pub fn write(&self, path: &Path, format: Format) -> Result<()> {
+        match format {
+            Format::Prometheus => {
+                let tmp = path.with_extension("tmp");
+                fs::write(&tmp, self.to_prometheus())
+                    .and_then(|()| fs::rename(&tmp, path))
+                    .with_context(|| format!("Failed to write metrics to {}", path.display()))
+            }
+            Format::Json => fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut f| writeln!(f, "{}", self.to_json()))
+                .with_context(|| format!("Failed to write metrics to {}", path.display())),
+        }
+    }
+
+    fn to_prometheus(&self) -> String {
+        let labels = format!(
+            "target_dir=\"{}\",dry_run=\"{}\"",
+            escape_label(&self.target_dir.display().to_string()),
+            self.dry_run,
+        );
+        let mut out = String::new();
+        let mut gauge = |name: &str, help: &str, extra: &str, value: String| {
+            if !out.contains(&format!("# TYPE {} ", name)) {
+                out += &format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name);
+            }
+            out += &format!("{}{{{}{}}} {}\n", name, labels, extra, value);
+        };
+        gauge(
+            "cargo_gc_target_size_before_bytes",
+            "Target directory size before GC.",
+            "",
+            self.size_before.to_string(),
+        );
+        gauge(
+            "cargo_gc_target_size_after_bytes",
+            "Target directory size after GC.",
+            "",
+            self.size_after.to_string(),
+        );
+        gauge(
+            "cargo_gc_freed_bytes",
+            "Bytes freed by GC.",
+            "",
+            self.freed_bytes.to_string(),
+        );
+        gauge(
+            "cargo_gc_duration_seconds",
+            "Duration of GC.",
+            "",
+            self.duration.as_secs_f64().to_string(),
+        );
+        gauge(
+            "cargo_gc_failures",
+            "Entries failed to be removed.",
+            "",
+            self.failures.to_string(),
+        );
+        for (category, count) in &self.removed {
+            gauge(
+                "cargo_gc_removed_entries",
+                "Removed entries by category.",
+                &format!(",category=\"{}\"", escape_label(category)),
+                count.to_string(),
+            );
+        }
+        gauge(
+            "cargo_gc_last_run_timestamp_seconds",
+            "Time of the GC run.",
+            "",
+            unix_time().to_string(),
+        );
+        out
+    }
+
+    fn to_json(&self) -> String {
+        serde_json::json!({
+            "timestamp": unix_time(),
+            "target_dir": self.target_dir.display().to_string(),
+            "dry_run": self.dry_run,
+            "size_before": self.size_before,
+            "size_after": self.size_after,
+            "freed_bytes": self.freed_bytes,
+            "duration_secs": self.duration.as_secs_f64(),
+            "failures": self.failures,
+            "removed": self.removed,
+        })
+        .to_string()
+    }
+}
+
+/// Escape a label value of the Prometheus text format, where only backslashes, double quotes
+/// and line feeds are escaped. Unlike `{:?}`, other characters are kept as is.
+fn escape_label(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => ret.push_str("\\\\"),
+            '"' => ret.push_str("\\\""),
+            '\n' => ret.push_str("\\n"),
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_label_escaping() {
+        assert_eq!(escape_label(r"C:\target"), r"C:\\target");
+        assert_eq!(escape_label("a\"b\nc"), r#"a\"b\nc"#);
+        // Kept as is, while `{:?}` would escape tabs and control characters.
+        assert_eq!(escape_label("a\tb\u{1b}c"), "a\tb\u{1b}c");
+    }
+}
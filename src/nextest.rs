@@ -0,0 +1,42 @@
+//! Test binaries referenced by cargo-nextest archives.
+use anyhow::{Context as _, Result};
+use serde_json::Value;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Paths of test binaries listed in the binaries metadata `path` of cargo-nextest, rebased
+/// onto `target_dir`.
+///
+/// The metadata is `target/nextest/binaries-metadata.json` in an archive, or the output of
+/// `cargo nextest list --list-type binaries-only --message-format json`.
+pub fn binaries(path: &Path, target_dir: &Path) -> Result<Vec<PathBuf>> {
+    let metadata = fs::read_to_string(path)
+        .ok()
+        .and_then(|s| serde_json::from_str::<Value>(&s).ok())
+        .with_context(|| format!("Invalid nextest metadata {}", path.display()))?;
+    // Paths are absolute on the machine creating the archive.
+    let orig_target_dir = metadata
+        .pointer("/rust-build-meta/target-directory")
+        .and_then(|v| v.as_str())
+        .map(Path::new);
+    let binaries = metadata
+        .get("rust-binaries")
+        .and_then(|v| v.as_object())
+        .with_context(|| format!("No `rust-binaries` in nextest metadata {}", path.display()))?;
+
+    let mut ret = Vec::new();
+    for binary in binaries.values() {
+        let binary_path = match binary.get("binary-path").and_then(|v| v.as_str()) {
+            Some(p) => Path::new(p),
+            None => continue,
+        };
+        let relative = orig_target_dir.and_then(|dir| binary_path.strip_prefix(dir).ok());
+        match relative {
+            Some(relative) => ret.push(target_dir.join(relative)),
+            None => ret.push(binary_path.to_owned()),
+        }
+    }
+    Ok(ret)
+}
@@ -0,0 +1,302 @@
+//! Conservative collection from on-disk information only, without a workspace.
+use crate::collect::Reachable;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+/// Variants of a package built within this period before its latest build are kept,
+/// since they are likely built together, eg. for different targets or features.
+const BUILD_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Find profile directories, which contain `.fingerprint`, in the target directory
+/// and its target triple subdirectories.
+pub fn profile_dirs(target_dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(target_dir)? {
+        let path = entry?.path();
+        if path.join(".fingerprint").is_dir() {
+            ret.push(path);
+        } else if path.is_dir() {
+            for entry in fs::read_dir(&path)? {
+                let path = entry?.path();
+                if path.join(".fingerprint").is_dir() {
+                    ret.push(path);
+                }
+            }
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
+
+/// Collect artifacts to keep in the profile directory `dir`.
+/// Uplifted files and unrecognized entries are always kept.
+pub fn collect(dir: &Path) -> io::Result<Reachable> {
+    let mut reachable = Reachable::default();
+
+    // Package name -> [(metadata hash, last modified time)]
+    let mut variants = HashMap::<String, Vec<(String, SystemTime)>>::new();
+    for name in list_names(&dir.join(".fingerprint"))? {
+        match split_hash(&name) {
+            Some((pkg, hash)) => {
+                let mtime = latest_mtime(&dir.join(".fingerprint").join(&name))?;
+                variants
+                    .entry(pkg.to_owned())
+                    .or_default()
+                    .push((hash.to_owned(), mtime));
+            }
+            None => {
+                reachable.fingerprints.insert(name);
+            }
+        }
+    }
+
+    let mut hashes = HashSet::new();
+    for variants in variants.values() {
+        let latest = variants.iter().map(|&(_, mtime)| mtime).max().unwrap();
+        for (hash, mtime) in variants {
+            if *mtime + BUILD_WINDOW >= latest {
+                hashes.insert(hash.clone());
+            }
+        }
+    }
+    let keeps = |stem: &str| split_hash(stem).map_or(true, |(_, hash)| hashes.contains(hash));
+
+    for name in list_names(&dir.join(".fingerprint"))? {
+        if keeps(&name) {
+            reachable.fingerprints.insert(name);
+        }
+    }
+    for name in list_names(&dir.join("build"))? {
+        if keeps(&name) {
+            reachable.builds.insert(name);
+        }
+    }
+    for name in list_names(&dir.join("deps"))? {
+        // `libfoo-0123456789abcdef.rlib`, `foo-0123456789abcdef.foo.1a2b3c-cgu.0.rcgu.o`
+        if keeps(name.split('.').next().unwrap()) {
+            reachable.deps.insert(name);
+        }
+    }
+    reachable.uplifts.extend(list_names(dir)?);
+
+    Ok(reachable)
+}
+
+/// Metadata hashes of all builds of packages `names` in the profile directory `dir`.
+/// Hyphens and underscores are not distinguished.
+pub fn package_hashes(dir: &Path, names: &[String]) -> io::Result<HashSet<String>> {
+    let normalize = |name: &str| name.replace('-', "_");
+    let names = names
+        .iter()
+        .map(|name| normalize(name))
+        .collect::<HashSet<_>>();
+    let mut ret = HashSet::new();
+    for name in list_names(&dir.join(".fingerprint"))? {
+        if let Some((pkg, hash)) = split_hash(&name) {
+            if names.contains(&normalize(pkg)) {
+                ret.insert(hash.to_owned());
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Crate names of artifacts in `deps` of the profile directory `dir` with metadata hashes
+/// in `hashes`, which may differ from their package names.
+pub fn crate_names(dir: &Path, hashes: &HashSet<String>) -> io::Result<HashSet<String>> {
+    let mut ret = HashSet::new();
+    for name in list_names(&dir.join("deps"))? {
+        // Dep-info files `{crate}-{hash}.d` are never prefixed by `lib`.
+        if let Some((crate_name, hash)) = name.strip_suffix(".d").and_then(split_hash) {
+            if hashes.contains(hash) {
+                ret.insert(crate_name.to_owned());
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Metadata hashes of builds in the profile directory `dir` which satisfy `pred` of their
+/// package names and whether they are host builds, ie. build scripts and proc-macros.
+/// Proc-macros are recognized by their dynamic libraries in `deps`.
+pub fn hashes_by_kind(
+    dir: &Path,
+    pred: impl Fn(&str, bool) -> bool,
+) -> io::Result<HashSet<String>> {
+    let mut dylib_hashes = HashSet::new();
+    for name in list_names(&dir.join("deps"))? {
+        let stem = [".so", ".dylib", ".dll"]
+            .iter()
+            .find_map(|ext| name.strip_suffix(ext));
+        if let Some((_, hash)) = stem.and_then(split_hash) {
+            dylib_hashes.insert(hash.to_owned());
+        }
+    }
+    let fingerprint_dir = dir.join(".fingerprint");
+    let mut ret = HashSet::new();
+    for name in list_names(&fingerprint_dir)? {
+        let (pkg, hash) = match split_hash(&name) {
+            Some(split) => split,
+            None => continue,
+        };
+        // Fingerprints of build scripts are `{build-script,run-build-script}-build-script-*`.
+        let is_build_script = list_names(&fingerprint_dir.join(&name))?
+            .iter()
+            .any(|file| file.starts_with("build-script-") || file.starts_with("run-build-script-"));
+        if pred(pkg, is_build_script || dylib_hashes.contains(hash)) {
+            ret.insert(hash.to_owned());
+        }
+    }
+    Ok(ret)
+}
+
+/// Metadata hashes of builds in the profile directory `dir` by package names,
+/// for packages not in `packages`.
+pub fn orphans(
+    dir: &Path,
+    packages: &HashSet<String>,
+) -> io::Result<BTreeMap<String, HashSet<String>>> {
+    let mut ret = BTreeMap::<_, HashSet<_>>::new();
+    for name in list_names(&dir.join(".fingerprint"))? {
+        if let Some((pkg, hash)) = split_hash(&name) {
+            if !packages.contains(pkg) {
+                ret.entry(pkg.to_owned())
+                    .or_default()
+                    .insert(hash.to_owned());
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Split `{name}-{hash}` where `hash` is a 16-digit hexadecimal metadata hash.
+pub fn split_hash(stem: &str) -> Option<(&str, &str)> {
+    let idx = stem.rfind('-')?;
+    let hash = &stem[idx + 1..];
+    if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        Some((&stem[..idx], hash))
+    } else {
+        None
+    }
+}
+
+pub fn list_names(dir: &Path) -> io::Result<Vec<String>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        if let Ok(name) = entry?.file_name().into_string() {
+            ret.push(name);
+        }
+    }
+    Ok(ret)
+}
+
+/// Whether `path` is an executable file, by permission bits on unix or by extension otherwise.
+pub fn is_executable(path: &Path) -> bool {
+    let meta = match fs::metadata(path) {
+        Ok(meta) if meta.is_file() => meta,
+        _ => return false,
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        meta.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = meta;
+        path.extension().map_or(false, |ext| ext == "exe")
+    }
+}
+
+/// The latest modified time of a directory and its direct children.
+pub fn latest_mtime(dir: &Path) -> io::Result<SystemTime> {
+    let mut ret = fs::metadata(dir)?.modified()?;
+    for entry in fs::read_dir(dir)? {
+        ret = ret.max(entry?.metadata()?.modified()?);
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    const BAR: &str = "1111111111111111";
+    const FOO: &str = "2222222222222222";
+    const BAZ: &str = "3333333333333333";
+
+    /// A profile directory with builds of
+    /// - package `bar`, depended on as `foo = { package = "bar" }`,
+    /// - an unrelated package `foo`,
+    /// - package `baz-pkg` with `[lib] name = "qux"`.
+    fn profile_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cargo-gc-{}-{}", name, std::process::id()));
+        for (pkg, crate_name, hash) in &[
+            ("bar", "bar", BAR),
+            ("foo", "foo", FOO),
+            ("baz-pkg", "qux", BAZ),
+        ] {
+            fs::create_dir_all(dir.join(".fingerprint").join(format!("{}-{}", pkg, hash))).unwrap();
+            fs::create_dir_all(dir.join("deps")).unwrap();
+            fs::write(
+                dir.join("deps")
+                    .join(format!("lib{}-{}.rlib", crate_name, hash)),
+                "",
+            )
+            .unwrap();
+            fs::write(
+                dir.join("deps").join(format!("{}-{}.d", crate_name, hash)),
+                "",
+            )
+            .unwrap();
+        }
+        dir
+    }
+
+    fn hashes(hashes: &[&str]) -> HashSet<String> {
+        hashes.iter().map(|&hash| hash.to_owned()).collect()
+    }
+
+    #[test]
+    fn renamed_dependency_hashes() {
+        let dir = profile_dir("renamed-hashes");
+        // Packages are matched by package names, never by extern names of dependents.
+        let bar = package_hashes(&dir, &["bar".to_owned()]).unwrap();
+        let foo = package_hashes(&dir, &["foo".to_owned()]).unwrap();
+        let baz = package_hashes(&dir, &["baz_pkg".to_owned()]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(bar, hashes(&[BAR]));
+        assert_eq!(foo, hashes(&[FOO]));
+        assert_eq!(baz, hashes(&[BAZ]));
+    }
+
+    #[test]
+    fn renamed_dependency_crate_names() {
+        let dir = profile_dir("renamed-crate-names");
+        let bar = crate_names(&dir, &hashes(&[BAR])).unwrap();
+        let baz = crate_names(&dir, &hashes(&[BAZ])).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(bar, ["bar".to_owned()].iter().cloned().collect());
+        assert_eq!(baz, ["qux".to_owned()].iter().cloned().collect());
+    }
+
+    #[test]
+    fn purge_renamed_dependency() {
+        let dir = profile_dir("renamed-purge");
+        let mut reachable = Reachable::default();
+        reachable.restrict(package_hashes(&dir, &["bar".to_owned()]).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(reachable.in_scope(&format!("bar-{}", BAR)));
+        assert!(reachable.in_scope(&format!("libbar-{}.rlib", BAR)));
+        assert!(!reachable.in_scope(&format!("foo-{}", FOO)));
+        assert!(!reachable.in_scope(&format!("libfoo-{}.rlib", FOO)));
+    }
+}
@@ -0,0 +1,92 @@
+//! Removal of entries the current user has no permission to remove, like those owned by root
+//! after builds in containers of `cross`.
+use anyhow::{bail, ensure, Context as _, Result};
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Number of paths passed to each `rm`, within limits of command lines.
+const CHUNK_SIZE: usize = 256;
+
+/// Paths in `paths` owned by root, or in directories owned by root.
+#[cfg(unix)]
+pub fn root_owned(paths: &[PathBuf]) -> Vec<&Path> {
+    use std::os::unix::fs::MetadataExt;
+
+    let is_root = |path: &Path| {
+        path.symlink_metadata()
+            .map_or(false, |meta| meta.uid() == 0)
+    };
+    paths
+        .iter()
+        .map(|path| &**path)
+        .filter(|path| is_root(path) || path.parent().map_or(false, is_root))
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub fn root_owned(_paths: &[PathBuf]) -> Vec<&Path> {
+    Vec::new()
+}
+
+/// Remove `paths` by `rm` run by `helper`, a command line like `sudo -n`.
+pub fn remove_with_helper(helper: &str, paths: &[PathBuf]) -> Result<()> {
+    let mut words = helper.split_whitespace();
+    let program = words.next().context("Empty privileged helper")?;
+    for chunk in paths.chunks(CHUNK_SIZE) {
+        let status = Command::new(program)
+            .args(words.clone())
+            .args(&["rm", "-rf", "--"])
+            .args(chunk)
+            .status()
+            .with_context(|| format!("Failed to run privileged helper `{}`", helper))?;
+        ensure!(
+            status.success(),
+            "Privileged helper `{}` failed: {}",
+            helper,
+            status
+        );
+    }
+    Ok(())
+}
+
+/// Remove `paths` in `target_dir` by `rm` in a container of `image`, with the target directory
+/// mounted, as `cross` does. The container engine is `$CROSS_CONTAINER_ENGINE` or `docker`.
+pub fn remove_in_container(image: &str, target_dir: &Path, paths: &[PathBuf]) -> Result<()> {
+    const MOUNT: &str = "/cargo-gc-target";
+
+    let engine = std::env::var_os("CROSS_CONTAINER_ENGINE").unwrap_or_else(|| "docker".into());
+    let mut volume = OsString::from(target_dir);
+    volume.push(":");
+    volume.push(MOUNT);
+    let mut args = Vec::with_capacity(paths.len());
+    for path in paths {
+        let rel = match path.strip_prefix(target_dir) {
+            Ok(rel) => rel,
+            Err(_) => bail!(
+                "{} is outside the target directory and can't be mounted",
+                path.display(),
+            ),
+        };
+        args.push(Path::new(MOUNT).join(rel));
+    }
+    for chunk in args.chunks(CHUNK_SIZE) {
+        let status = Command::new(&engine)
+            .args(&["run", "--rm", "--entrypoint", "rm", "-v"])
+            .arg(&volume)
+            .arg(image)
+            .args(&["-rf", "--"])
+            .args(chunk)
+            .status()
+            .with_context(|| format!("Failed to run {}", engine.to_string_lossy()))?;
+        ensure!(
+            status.success(),
+            "Removal in container `{}` failed: {}",
+            image,
+            status
+        );
+    }
+    Ok(())
+}
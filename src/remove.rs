@@ -0,0 +1,1019 @@
+use crate::{size, timing};
+use anyhow::Result;
+use bytesize::ByteSize;
+use cargo::Config;
+use filetime::FileTime;
+use std::{
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use std::collections::HashSet;
+#[cfg(unix)]
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString, OsStr},
+    os::unix::{ffi::OsStrExt, io::RawFd},
+};
+
+/// Removes files and directories while accounting the freed bytes.
+pub struct Remover<'cfg> {
+    config: &'cfg Config,
+    pub dry_run: bool,
+    /// Report each removal with its reason regardless of verbosity.
+    pub why: bool,
+    /// Record per-entry IO errors in `failures` instead of stopping at the first one.
+    pub keep_going: bool,
+    /// Schedule files locked by other processes for deletion on reboot. Windows only.
+    pub defer_locked: bool,
+    /// Visit directory entries in sorted order, so that removals happen in a stable order.
+    pub deterministic: bool,
+    /// Number of threads to unlink files in a directory with. Parallel unlinking is disabled
+    /// when throttled or deterministic.
+    pub jobs: usize,
+    /// Limit the rate of removals.
+    pub throttle: Option<Throttle>,
+    /// Start time, removed entries and bytes counted for throttling.
+    throttled: Option<(Instant, u64, u64)>,
+    /// Skip size accounting where it costs extra stats, and only count entries.
+    /// `freed_bytes` is incomplete then.
+    pub no_size: bool,
+    pub freed_bytes: u64,
+    /// Numbers of removed files, including symlinks, and directories.
+    pub removed_files: u64,
+    pub removed_dirs: u64,
+    /// Paths which cannot be removed due to insufficient permissions.
+    pub skipped: Vec<PathBuf>,
+    /// Errors tolerated because of `keep_going`.
+    pub failures: Vec<(PathBuf, io::Error)>,
+    /// Locked files scheduled for deletion on reboot because of `defer_locked`.
+    pub deferred: Vec<PathBuf>,
+    /// Paths never removed. Their ancestors are kept as well.
+    pub protected: HashSet<PathBuf>,
+    /// If set, record paths passed to `remove` with reasons.
+    pub plan: Option<Vec<(PathBuf, String)>>,
+    /// Paths removed by `remove`, or to be removed in dry-run.
+    removed: HashSet<PathBuf>,
+    /// Directories being removed by background threads.
+    background: Vec<(PathBuf, thread::JoinHandle<io::Result<()>>)>,
+    /// `(device, inode)` of multiply-linked files -> (links seen, link count when first seen).
+    /// Uplifted binaries are usually hard links to `deps/` outputs.
+    #[cfg(unix)]
+    seen_links: HashMap<(u64, u64), (u64, u64)>,
+}
+
+impl<'cfg> Remover<'cfg> {
+    pub fn new(config: &'cfg Config, dry_run: bool) -> Self {
+        Self {
+            config,
+            dry_run,
+            why: false,
+            keep_going: false,
+            defer_locked: false,
+            deterministic: false,
+            jobs: 1,
+            throttle: None,
+            throttled: None,
+            no_size: false,
+            freed_bytes: 0,
+            removed_files: 0,
+            removed_dirs: 0,
+            skipped: Vec::new(),
+            failures: Vec::new(),
+            deferred: Vec::new(),
+            protected: HashSet::new(),
+            plan: None,
+            removed: HashSet::new(),
+            background: Vec::new(),
+            #[cfg(unix)]
+            seen_links: HashMap::new(),
+        }
+    }
+
+    /// Report and remove `path` recursively because of `reason`. Protected paths are kept, and
+    /// directories containing them are removed entry by entry, so that only paths actually
+    /// removed, or to be removed in dry-run, are recorded in `plan` and `removed`.
+    pub fn remove(&mut self, path: &Path, reason: impl fmt::Display) -> Result<()> {
+        if self.protected.contains(path) {
+            tracing::debug!("Keeping protected {}", path.display());
+            return Ok(());
+        }
+        if self.protects_under(path) && path.is_dir() {
+            let reason = reason.to_string();
+            for entry in self.read_dir(path)? {
+                self.remove(&entry.path(), &reason)?;
+            }
+            return Ok(());
+        }
+
+        let skipped = if self.dry_run { "(skipped) " } else { "" };
+        if self.why {
+            self.config.shell().status(
+                "Removing",
+                format_args!("{}{}: {}", skipped, path.display(), reason),
+            )?;
+        } else {
+            self.config
+                .shell()
+                .verbose(|s| s.status("Removing", format_args!("{}{}", skipped, path.display())))?;
+        }
+        // Skipped for permissions, deferred or failed with `keep_going` otherwise.
+        if !timing::measure("deletion", || self.remove_tree(path, None))? {
+            return Ok(());
+        }
+        if let Some(plan) = &mut self.plan {
+            plan.push((path.to_owned(), reason.to_string()));
+        }
+        self.removed.insert(path.to_owned());
+        Ok(())
+    }
+
+    /// Whether `path` or any of its ancestors has been removed.
+    pub fn is_removed(&self, path: &Path) -> bool {
+        path.ancestors().any(|p| self.removed.contains(p))
+    }
+
+    /// Paths removed by `remove`, or to be removed in dry-run.
+    pub fn removed(&self) -> impl Iterator<Item = &Path> {
+        self.removed.iter().map(|p| &**p)
+    }
+
+    /// Account removals planned by a dry-run `plan`, which are carried out by other means.
+    pub fn absorb(&mut self, plan: Remover) {
+        self.freed_bytes += plan.freed_bytes;
+        self.removed_files += plan.removed_files;
+        self.removed_dirs += plan.removed_dirs;
+        self.removed.extend(plan.removed);
+        if let (Some(entries), Some(planned)) = (&mut self.plan, plan.plan) {
+            entries.extend(planned);
+        }
+    }
+
+    /// Remove `dir` recursively in a background thread, without accounting or throttling.
+    /// Wait for it by `join_background`.
+    pub fn remove_in_background(&mut self, dir: PathBuf) {
+        let path = dir.clone();
+        let handle = thread::spawn(move || fs::remove_dir_all(path));
+        self.background.push((dir, handle));
+    }
+
+    /// Wait for all background removals. Errors are handled by `tolerate`.
+    pub fn join_background(&mut self) -> io::Result<()> {
+        for (dir, handle) in std::mem::take(&mut self.background) {
+            if let Err(err) = handle.join().expect("Background removal panicked") {
+                self.tolerate(&dir, err)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record `err` occurred on `path` if `keep_going` is set, or return it otherwise.
+    pub fn tolerate(&mut self, path: &Path, err: io::Error) -> io::Result<()> {
+        if !self.keep_going {
+            return Err(err);
+        }
+        tracing::debug!("Ignoring error on {}: {}", path.display(), err);
+        self.failures.push((path.to_owned(), err));
+        Ok(())
+    }
+
+    /// List entries of directory `dir`. Errors are handled by `tolerate`.
+    pub fn read_dir(&mut self, dir: &Path) -> io::Result<Vec<fs::DirEntry>> {
+        let start = Instant::now();
+        let mut ret = Vec::new();
+        match fs::read_dir(dir) {
+            Ok(iter) => {
+                for entry in iter {
+                    match entry {
+                        Ok(entry) => ret.push(entry),
+                        Err(err) => self.tolerate(dir, err)?,
+                    }
+                }
+            }
+            Err(err) => self.tolerate(dir, err)?,
+        }
+        if self.deterministic {
+            ret.sort_by_key(|entry| entry.file_name());
+        }
+        timing::record("directory walk", start.elapsed());
+        Ok(ret)
+    }
+
+    /// Remove `path` recursively and return whether it is fully removed. `meta` is reused if
+    /// already read from its directory entry.
+    /// Entries with permission errors are recorded in `skipped` instead of failing the whole
+    /// removal, and other errors are handled by `tolerate`.
+    fn remove_tree(&mut self, path: &Path, meta: Option<fs::Metadata>) -> io::Result<bool> {
+        if self.protected.contains(path) {
+            tracing::debug!("Keeping protected {}", path.display());
+            return Ok(false);
+        }
+        match self.remove_entry(path, meta) {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                tracing::debug!("Skipping {}: {}", path.display(), err);
+                self.skipped.push(path.to_owned());
+                Ok(false)
+            }
+            Err(err) => self.tolerate(path, err).map(|()| false),
+            ret => ret,
+        }
+    }
+
+    fn remove_entry(&mut self, path: &Path, meta: Option<fs::Metadata>) -> io::Result<bool> {
+        let meta = match meta {
+            Some(meta) => meta,
+            None => path.symlink_metadata()?,
+        };
+        if meta.is_dir() && self.dry_run && self.no_size && !self.protects_under(path) {
+            let (files, dirs) = count_tree(path)?;
+            self.removed_files += files;
+            self.removed_dirs += dirs;
+            return Ok(true);
+        }
+        // Only accounting is done in dry-run, which is spread across threads. Errors are left
+        // to the sequential walk, which handles permissions.
+        if meta.is_dir() && self.dry_run && self.jobs > 1 && !self.protects_under(path) {
+            if let Ok(sizes) = size::par_walk(path, self.jobs, TreeSize::add) {
+                for size in sizes {
+                    self.freed_bytes += size.bytes;
+                    self.removed_files += size.files;
+                    self.removed_dirs += size.dirs;
+                    for meta in size.links {
+                        if self.is_freed(&meta) {
+                            self.freed_bytes += meta.len();
+                        }
+                    }
+                }
+                return Ok(true);
+            }
+        }
+        #[cfg(unix)]
+        {
+            if let (true, false, Some(parent), Some(name)) =
+                (meta.is_dir(), self.dry_run, path.parent(), path.file_name())
+            {
+                let parent = at::Dir::open(parent)?;
+                return self.remove_entry_at(parent.fd(), &at::cstring(name)?, path, Some(true));
+            }
+        }
+        if meta.is_dir() {
+            let mut all_removed = true;
+            // Metadata of directory entries is read relative to the directory, or returned by
+            // the directory listing on Windows.
+            let mut entries = self
+                .fix_permission(path, || fs::read_dir(path))?
+                .map(|entry| entry.map(|entry| (entry.path(), entry.metadata().ok())))
+                .collect::<io::Result<Vec<_>>>()?;
+            if self.deterministic {
+                entries.sort_by(|(lhs, _), (rhs, _)| lhs.cmp(rhs));
+            }
+            #[cfg(not(unix))]
+            {
+                if self.jobs > 1 && !self.dry_run && !self.deterministic && self.throttle.is_none()
+                {
+                    entries = self.unlink_files(entries);
+                }
+            }
+            for (entry, meta) in entries {
+                all_removed &= self.remove_tree(&entry, meta)?;
+            }
+            if !all_removed {
+                return Ok(false);
+            }
+            if !self.dry_run {
+                self.fix_permission(path, || fs::remove_dir(path))?;
+                self.throttle(meta.len());
+            }
+            self.freed_bytes += meta.len();
+            self.removed_dirs += 1;
+        } else {
+            if !self.dry_run {
+                match self.remove_file(path) {
+                    Err(err) if is_locked(&err) && self.defer_locked => {
+                        tracing::debug!("Deferring {}: {}", path.display(), err);
+                        delete_on_reboot(path)?;
+                        self.deferred.push(path.to_owned());
+                        return Ok(false);
+                    }
+                    ret => ret?,
+                }
+                self.throttle(meta.len());
+            }
+            if self.is_freed(&meta) {
+                self.freed_bytes += meta.len();
+            }
+            self.removed_files += 1;
+        }
+        Ok(true)
+    }
+
+    /// Unlink regular files in `paths` with `jobs` threads, and return remaining paths,
+    /// including failed ones, to be removed sequentially.
+    /// Most entries in `.fingerprint` and `incremental` are small files, where the time is
+    /// dominated by syscalls rather than IO.
+    #[cfg(not(unix))]
+    fn unlink_files(
+        &mut self,
+        paths: Vec<(PathBuf, Option<fs::Metadata>)>,
+    ) -> Vec<(PathBuf, Option<fs::Metadata>)> {
+        let mut rest = Vec::new();
+        let mut files = Vec::new();
+        for (path, meta) in paths {
+            match meta.map_or_else(|| path.symlink_metadata(), Ok) {
+                Ok(meta) if meta.is_file() && !self.protected.contains(&path) => {
+                    files.push((path, meta))
+                }
+                meta => rest.push((path, meta.ok())),
+            }
+        }
+        if files.len() < 2 {
+            rest.extend(files.into_iter().map(|(path, meta)| (path, Some(meta))));
+            return rest;
+        }
+
+        let chunk_size = (files.len() + self.jobs - 1) / self.jobs;
+        let handles = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let paths = chunk
+                    .iter()
+                    .map(|(path, _)| path.clone())
+                    .collect::<Vec<_>>();
+                thread::spawn(move || {
+                    paths
+                        .iter()
+                        .map(|path| fs::remove_file(path).is_ok())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("Unlinking thread panicked"));
+        for ((path, meta), removed) in files.into_iter().zip(results) {
+            if !removed {
+                rest.push((path, None));
+                continue;
+            }
+            if self.is_freed(&meta) {
+                self.freed_bytes += meta.len();
+            }
+            self.removed_files += 1;
+        }
+        rest
+    }
+
+    /// Sleep if removals run faster than `throttle` allows, after removing an entry of `bytes`.
+    fn throttle(&mut self, bytes: u64) {
+        let throttle = match self.throttle {
+            Some(throttle) => throttle,
+            None => return,
+        };
+        let (start, ops, total_bytes) = self.throttled.get_or_insert((Instant::now(), 0, 0));
+        *ops += 1;
+        *total_bytes += bytes;
+        let expected = match throttle {
+            Throttle::Ops(rate) => Duration::from_secs_f64(*ops as f64 / rate as f64),
+            Throttle::Bytes(rate) => Duration::from_secs_f64(*total_bytes as f64 / rate as f64),
+        };
+        if let Some(ahead) = expected.checked_sub(start.elapsed()) {
+            thread::sleep(ahead);
+        }
+    }
+
+    /// Remove a file, retrying for a while if it is locked by another process.
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        const LOCKED_RETRIES: u32 = 5;
+        let mut retries = 0;
+        loop {
+            match self.fix_permission(path, || fs::remove_file(path)) {
+                Err(err) if is_locked(&err) && retries < LOCKED_RETRIES => {
+                    retries += 1;
+                    tracing::debug!("Retry #{} on locked {}", retries, path.display());
+                    thread::sleep(Duration::from_millis(50 << retries));
+                }
+                ret => return ret,
+            }
+        }
+    }
+
+    /// Run `op`, and retry once after granting the owner access to `path` and its parent
+    /// if it fails with a permission error.
+    fn fix_permission<T>(&self, path: &Path, op: impl Fn() -> io::Result<T>) -> io::Result<T> {
+        match op() {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied && !self.dry_run => {
+                tracing::debug!("Granting access to retry on {}", path.display());
+                if let Some(parent) = path.parent() {
+                    let _ = make_accessible(parent);
+                }
+                let _ = make_accessible(path);
+                op()
+            }
+            ret => ret,
+        }
+    }
+
+    /// Whether `path` or anything under it is protected.
+    fn protects_under(&self, path: &Path) -> bool {
+        self.protected.iter().any(|p| p.starts_with(path))
+    }
+
+    /// Whether the size of a file removed with `nlink` links is freed, ie. it is the last
+    /// link, or all links when first seen are removed in this run. Files with links kept
+    /// elsewhere are never counted.
+    #[cfg(unix)]
+    fn is_last_link(&mut self, dev: u64, ino: u64, nlink: u64) -> bool {
+        if nlink <= 1 {
+            return true;
+        }
+        let (seen, total) = self.seen_links.entry((dev, ino)).or_insert((0, nlink));
+        *seen += 1;
+        *seen >= *total
+    }
+
+    #[cfg(unix)]
+    fn is_freed(&mut self, meta: &fs::Metadata) -> bool {
+        use std::os::unix::fs::MetadataExt;
+        self.is_last_link(meta.dev(), meta.ino(), meta.nlink())
+    }
+
+    #[cfg(unix)]
+    fn is_freed_at(&mut self, st: &libc::stat) -> bool {
+        self.is_last_link(st.st_dev as u64, st.st_ino as u64, st.st_nlink as u64)
+    }
+
+    #[cfg(not(unix))]
+    fn is_freed(&mut self, _meta: &fs::Metadata) -> bool {
+        true
+    }
+}
+
+/// Bytes of a tree accounted by threads, except multiply-linked files to be deduplicated.
+#[derive(Default)]
+struct TreeSize {
+    bytes: u64,
+    links: Vec<fs::Metadata>,
+    files: u64,
+    dirs: u64,
+}
+
+impl TreeSize {
+    fn add(&mut self, _path: &Path, meta: &fs::Metadata) {
+        if meta.is_dir() {
+            self.dirs += 1;
+        } else {
+            self.files += 1;
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if !meta.is_dir() && meta.nlink() > 1 {
+                self.links.push(meta.clone());
+                return;
+            }
+        }
+        self.bytes += meta.len();
+    }
+}
+
+/// Numbers of files and directories in the directory `path`, including itself.
+/// Types are taken from directory entries, which need no stat on most platforms.
+fn count_tree(path: &Path) -> io::Result<(u64, u64)> {
+    let (mut files, mut dirs) = (0, 1);
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            let (sub_files, sub_dirs) = count_tree(&entry.path())?;
+            files += sub_files;
+            dirs += sub_dirs;
+        } else {
+            files += 1;
+        }
+    }
+    Ok((files, dirs))
+}
+
+/// Removal through directory file descriptors. Each entry is resolved relative to its parent
+/// directory, which avoids resolving full paths repeatedly, works for trees deeper than
+/// `PATH_MAX`, and is robust against concurrent renames of ancestors.
+/// Paths are only maintained for bookkeeping and reporting.
+#[cfg(unix)]
+impl<'cfg> Remover<'cfg> {
+    /// Like `remove_tree`, for entry `name` in directory `parent`, at `path`.
+    /// `is_dir` is the type from the directory entry if known.
+    fn remove_tree_at(
+        &mut self,
+        parent: RawFd,
+        name: &CStr,
+        path: &Path,
+        is_dir: Option<bool>,
+    ) -> io::Result<bool> {
+        if self.protected.contains(path) {
+            tracing::debug!("Keeping protected {}", path.display());
+            return Ok(false);
+        }
+        match self.remove_entry_at(parent, name, path, is_dir) {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                tracing::debug!("Skipping {}: {}", path.display(), err);
+                self.skipped.push(path.to_owned());
+                Ok(false)
+            }
+            Err(err) => self.tolerate(path, err).map(|()| false),
+            ret => ret,
+        }
+    }
+
+    fn remove_entry_at(
+        &mut self,
+        parent: RawFd,
+        name: &CStr,
+        path: &Path,
+        is_dir: Option<bool>,
+    ) -> io::Result<bool> {
+        // With `no_size`, directories known from their entries are not stat-ed, and others
+        // are tried to be unlinked first. It fails on directories, and on files needing
+        // permission fixes, which are handled below.
+        let mut dir_size = 0;
+        if !self.no_size || is_dir != Some(true) {
+            if self.no_size && at::unlink(parent, name, false).is_ok() {
+                self.throttle(0);
+                self.removed_files += 1;
+                return Ok(true);
+            }
+            let st = at::stat(parent, name)?;
+            if !at::is_dir(&st) {
+                self.fix_permission(path, || at::unlink(parent, name, false))?;
+                self.throttle(st.st_size as u64);
+                if self.is_freed_at(&st) {
+                    self.freed_bytes += st.st_size as u64;
+                }
+                self.removed_files += 1;
+                return Ok(true);
+            }
+            dir_size = st.st_size as u64;
+        }
+
+        let mut dir = self.fix_permission(path, || at::Dir::open_at(parent, name))?;
+        let mut entries = dir.entries()?;
+        if self.deterministic {
+            entries.sort();
+        }
+        if self.jobs > 1 && !self.deterministic && self.throttle.is_none() {
+            entries = self.unlink_files_at(dir.fd(), path, entries);
+        }
+        let mut all_removed = true;
+        for (name, is_dir) in entries {
+            let child = path.join(OsStr::from_bytes(name.to_bytes()));
+            all_removed &= self.remove_tree_at(dir.fd(), &name, &child, is_dir)?;
+        }
+        drop(dir);
+        if !all_removed {
+            return Ok(false);
+        }
+        self.fix_permission(path, || at::unlink(parent, name, true))?;
+        self.throttle(dir_size);
+        self.freed_bytes += dir_size;
+        self.removed_dirs += 1;
+        Ok(true)
+    }
+
+    /// Like `unlink_files`, for `entries` in directory `dir` at `path`.
+    /// With `no_size`, entries not known to be directories are tried without stats, and
+    /// directories fail to be unlinked.
+    fn unlink_files_at(
+        &mut self,
+        dir: RawFd,
+        path: &Path,
+        entries: Vec<(CString, Option<bool>)>,
+    ) -> Vec<(CString, Option<bool>)> {
+        let mut rest = Vec::new();
+        let mut files = Vec::new();
+        for (name, is_dir) in entries {
+            let child = path.join(OsStr::from_bytes(name.to_bytes()));
+            if is_dir == Some(true) || self.protected.contains(&child) {
+                rest.push((name, is_dir));
+                continue;
+            }
+            if self.no_size {
+                files.push((name, None));
+                continue;
+            }
+            match at::stat(dir, &name) {
+                Ok(st) if !at::is_dir(&st) => files.push((name, Some(st))),
+                _ => rest.push((name, is_dir)),
+            }
+        }
+        if files.len() < 2 {
+            rest.extend(files.into_iter().map(|(name, _)| (name, None)));
+            return rest;
+        }
+
+        let chunk_size = (files.len() + self.jobs - 1) / self.jobs;
+        let handles = files
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let names = chunk
+                    .iter()
+                    .map(|(name, _)| name.clone())
+                    .collect::<Vec<_>>();
+                // `dir` outlives the threads since they are joined below.
+                thread::spawn(move || {
+                    names
+                        .iter()
+                        .map(|name| at::unlink(dir, name, false).is_ok())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = handles
+            .into_iter()
+            .flat_map(|handle| handle.join().expect("Unlinking thread panicked"));
+        for ((name, st), removed) in files.into_iter().zip(results) {
+            if !removed {
+                rest.push((name, None));
+                continue;
+            }
+            if let Some(st) = st {
+                if self.is_freed_at(&st) {
+                    self.freed_bytes += st.st_size as u64;
+                }
+            }
+            self.removed_files += 1;
+        }
+        rest
+    }
+}
+
+#[cfg(unix)]
+mod at {
+    use std::{
+        ffi::{CStr, CString, OsStr},
+        io,
+        mem::MaybeUninit,
+        os::unix::{ffi::OsStrExt, io::RawFd},
+        path::Path,
+    };
+
+    pub fn cstring(s: &OsStr) -> io::Result<CString> {
+        CString::new(s.as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+
+    pub fn stat(dir: RawFd, name: &CStr) -> io::Result<libc::stat> {
+        let mut st = MaybeUninit::uninit();
+        let ret = unsafe {
+            libc::fstatat(
+                dir,
+                name.as_ptr(),
+                st.as_mut_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { st.assume_init() })
+    }
+
+    pub fn is_dir(st: &libc::stat) -> bool {
+        st.st_mode & libc::S_IFMT == libc::S_IFDIR
+    }
+
+    pub fn unlink(dir: RawFd, name: &CStr, is_dir: bool) -> io::Result<()> {
+        let flags = if is_dir { libc::AT_REMOVEDIR } else { 0 };
+        if unsafe { libc::unlinkat(dir, name.as_ptr(), flags) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// An open directory stream, closed on drop.
+    pub struct Dir(*mut libc::DIR);
+
+    impl Dir {
+        pub fn open(path: &Path) -> io::Result<Self> {
+            Self::open_at(libc::AT_FDCWD, &cstring(path.as_os_str())?)
+        }
+
+        /// Open directory `name` in directory `dir` without following symlinks.
+        pub fn open_at(dir: RawFd, name: &CStr) -> io::Result<Self> {
+            let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+            let fd = unsafe { libc::openat(dir, name.as_ptr(), flags) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let stream = unsafe { libc::fdopendir(fd) };
+            if stream.is_null() {
+                let err = io::Error::last_os_error();
+                unsafe { libc::close(fd) };
+                return Err(err);
+            }
+            Ok(Self(stream))
+        }
+
+        pub fn fd(&self) -> RawFd {
+            unsafe { libc::dirfd(self.0) }
+        }
+
+        /// Names of all entries except `.` and `..`, with whether they are directories if
+        /// the filesystem reports types of entries.
+        pub fn entries(&mut self) -> io::Result<Vec<(CString, Option<bool>)>> {
+            let mut ret = Vec::new();
+            loop {
+                let entry = unsafe { libc::readdir(self.0) };
+                if entry.is_null() {
+                    break;
+                }
+                let name = unsafe { CStr::from_ptr((*entry).d_name.as_ptr()) };
+                if name.to_bytes() != b"." && name.to_bytes() != b".." {
+                    ret.push((name.to_owned(), entry_is_dir(unsafe { &*entry })));
+                }
+            }
+            Ok(ret)
+        }
+    }
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+    ))]
+    fn entry_is_dir(entry: &libc::dirent) -> Option<bool> {
+        match entry.d_type {
+            libc::DT_UNKNOWN => None,
+            ty => Some(ty == libc::DT_DIR),
+        }
+    }
+
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_os = "dragonfly",
+    )))]
+    fn entry_is_dir(_entry: &libc::dirent) -> Option<bool> {
+        None
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            unsafe { libc::closedir(self.0) };
+        }
+    }
+}
+
+/// Maximum rate of removals.
+#[derive(Debug, Clone, Copy)]
+pub enum Throttle {
+    /// Entries per second.
+    Ops(u64),
+    /// Bytes per second.
+    Bytes(u64),
+}
+
+impl FromStr for Throttle {
+    type Err = String;
+
+    /// Parse `100` as 100 entries per second, or `20MiB` as 20 MiB per second.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ret = match s.parse::<u64>() {
+            Ok(ops) => Self::Ops(ops),
+            Err(_) => Self::Bytes(s.parse::<ByteSize>()?.as_u64()),
+        };
+        match ret {
+            Self::Ops(0) | Self::Bytes(0) => Err("Throttle rate must be positive".into()),
+            ret => Ok(ret),
+        }
+    }
+}
+
+/// Grant the owner full access to a directory or write access to a file.
+/// Symlinks are left untouched since permissions would apply to their targets.
+fn make_accessible(path: &Path) -> io::Result<()> {
+    let meta = path.symlink_metadata()?;
+    if meta.file_type().is_symlink() {
+        return Ok(());
+    }
+    let mut perms = meta.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let bits = if meta.is_dir() { 0o700 } else { 0o200 };
+        perms.set_mode(perms.mode() | bits);
+    }
+    #[cfg(not(unix))]
+    perms.set_readonly(false);
+    fs::set_permissions(path, perms)
+}
+
+/// Whether the error indicates the file is opened by another process.
+#[cfg(windows)]
+fn is_locked(err: &io::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+    matches!(
+        err.raw_os_error(),
+        Some(ERROR_SHARING_VIOLATION) | Some(ERROR_LOCK_VIOLATION)
+    )
+}
+
+#[cfg(not(windows))]
+fn is_locked(_err: &io::Error) -> bool {
+    false
+}
+
+#[cfg(windows)]
+fn delete_on_reboot(path: &Path) -> io::Result<()> {
+    use std::os::windows::ffi::OsStrExt;
+
+    const MOVEFILE_DELAY_UNTIL_REBOOT: u32 = 0x4;
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn MoveFileExW(existing: *const u16, new: *const u16, flags: u32) -> i32;
+    }
+
+    let path = path
+        .as_os_str()
+        .encode_wide()
+        .chain(Some(0))
+        .collect::<Vec<u16>>();
+    let ret = unsafe { MoveFileExW(path.as_ptr(), std::ptr::null(), MOVEFILE_DELAY_UNTIL_REBOOT) };
+    if ret == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn delete_on_reboot(_path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Delete-on-reboot is only supported on Windows",
+    ))
+}
+
+/// Set modification times of `root` and all directories under it to `mtime`.
+/// Symlinks are not followed.
+pub fn normalize_dir_mtimes(root: &Path, mtime: FileTime) -> io::Result<()> {
+    for entry in fs::read_dir(root)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            normalize_dir_mtimes(&entry.path(), mtime)?;
+        }
+    }
+    filetime::set_file_mtime(root, mtime)
+}
+
+/// Lexically resolve `.` and `..` components without accessing the filesystem.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut ret = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                ret.pop();
+            }
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+/// Convert an absolute path to the extended-length form `\\?\C:\...` on Windows,
+/// so that deeply nested artifacts beyond `MAX_PATH` can still be accessed.
+/// Paths derived from the result by `join` keep the extended form.
+#[cfg(windows)]
+pub fn extended_path(path: &Path) -> PathBuf {
+    use std::{ffi::OsString, path::Prefix};
+
+    let mut components = path.components();
+    let prefix = match components.next() {
+        Some(Component::Prefix(prefix)) => prefix,
+        _ => return path.to_owned(),
+    };
+    let mut root = match prefix.kind() {
+        Prefix::Disk(_) => {
+            let mut s = OsString::from(r"\\?\");
+            s.push(prefix.as_os_str());
+            s
+        }
+        Prefix::UNC(server, share) => {
+            let mut s = OsString::from(r"\\?\UNC\");
+            s.push(server);
+            s.push(r"\");
+            s.push(share);
+            s
+        }
+        // Already verbatim or a device path.
+        _ => return path.to_owned(),
+    };
+    root.push(r"\");
+
+    // Verbatim paths are not normalized by Windows. Do it ourselves.
+    let mut ret = PathBuf::from(root);
+    for component in components {
+        match component {
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+            Component::ParentDir => {
+                ret.pop();
+            }
+            Component::Normal(name) => ret.push(name),
+        }
+    }
+    ret
+}
+
+#[cfg(not(windows))]
+pub fn extended_path(path: &Path) -> PathBuf {
+    path.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[cfg(windows)]
+    #[test]
+    fn extended_disk_path() {
+        assert_eq!(
+            extended_path(Path::new(r"C:\target\..\debug\.\deps")),
+            Path::new(r"\\?\C:\debug\deps"),
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn extended_unc_path() {
+        assert_eq!(
+            extended_path(Path::new(r"\\server\share\target\debug")),
+            Path::new(r"\\?\UNC\server\share\target\debug"),
+        );
+        // Already extended.
+        let verbatim = Path::new(r"\\?\C:\target");
+        assert_eq!(extended_path(verbatim), verbatim);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn remove_beyond_max_path() {
+        let root = extended_path(
+            &env::temp_dir().join(format!("cargo-gc-long-path-{}", std::process::id())),
+        );
+        let mut deep = root.clone();
+        while deep.as_os_str().len() <= 2 * 260 {
+            deep.push("a".repeat(32));
+        }
+        fs::create_dir_all(&deep).unwrap();
+        fs::write(deep.join("lib.rlib"), "").unwrap();
+
+        let config = Config::default().unwrap();
+        let mut remover = Remover::new(&config, false);
+        remover.remove(&root, "test").unwrap();
+        assert!(!root.exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_link_sizes() {
+        let root = env::temp_dir().join(format!("cargo-gc-hard-links-{}", std::process::id()));
+        let config = Config::default().unwrap();
+        for &(dry_run, keep_link) in &[(true, true), (false, true), (false, false)] {
+            let removed = root.join("removed");
+            let kept = root.join("kept");
+            fs::create_dir_all(&removed).unwrap();
+            fs::create_dir_all(&kept).unwrap();
+            fs::write(removed.join("deps-bin"), vec![0; 4096]).unwrap();
+            let link = if keep_link {
+                kept.join("bin")
+            } else {
+                removed.join("bin")
+            };
+            fs::hard_link(removed.join("deps-bin"), link).unwrap();
+            let dir_size = fs::metadata(&removed).unwrap().len();
+
+            let mut remover = Remover::new(&config, dry_run);
+            remover.remove(&removed, "test").unwrap();
+            let file_size = if keep_link { 0 } else { 4096 };
+            assert_eq!(
+                remover.freed_bytes,
+                dir_size + file_size,
+                "dry_run: {}, keep_link: {}",
+                dry_run,
+                keep_link,
+            );
+            fs::remove_dir_all(&root).unwrap();
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Self-test building a throwaway project with the cargo of the user, to check that GC with
+//! hashes computed by cargo-gc never removes artifacts the cargo reuses.
+use anyhow::{ensure, Context as _, Result};
+use cargo::Config;
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Files of the project, with a build script, a path dependency and an integration test.
+const FILES: &[(&str, &str)] = &[
+    (
+        "Cargo.toml",
+        r#"[package]
+name = "cargo-gc-self-test"
+version = "0.1.0"
+edition = "2018"
+
+[dependencies]
+dep = { package = "cargo-gc-self-test-dep", path = "dep", features = ["extra"] }
+
+[workspace]
+"#,
+    ),
+    (
+        "build.rs",
+        "fn main() {\n    println!(\"cargo:rerun-if-changed=build.rs\");\n}\n",
+    ),
+    ("src/main.rs", "fn main() {\n    dep::hello();\n}\n"),
+    ("tests/it.rs", "#[test]\nfn it() {}\n"),
+    (
+        "dep/Cargo.toml",
+        r#"[package]
+name = "cargo-gc-self-test-dep"
+version = "0.1.0"
+edition = "2018"
+
+[features]
+extra = []
+"#,
+    ),
+    ("dep/src/lib.rs", "pub fn hello() {}\n"),
+];
+
+/// Cargo commands building artifacts of all kinds in the project.
+pub const BUILDS: &[&[&str]] = &[
+    &["build"],
+    &["build", "--release"],
+    &["check", "--all-targets"],
+    &["test", "--no-run"],
+];
+
+pub fn create_project(dir: &Path) -> Result<()> {
+    for (path, content) in FILES {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap())?;
+        fs::write(&path, content).with_context(|| format!("Failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Bump the version of the dependency, so its previous artifacts become unreachable.
+pub fn bump_dependency(dir: &Path) -> Result<()> {
+    let path = dir.join("dep/Cargo.toml");
+    let manifest = fs::read_to_string(&path)?;
+    fs::write(&path, manifest.replace("0.1.0", "0.1.1"))?;
+    Ok(())
+}
+
+/// The target directory of the project `dir`, overriding `CARGO_TARGET_DIR` and
+/// `build.target-dir` of the user, which may be shared with real workspaces.
+pub fn target_dir(dir: &Path) -> PathBuf {
+    dir.join("target")
+}
+
+/// Run `cargo {args}` in the project `dir`.
+pub fn run_cargo(config: &Config, cargo: &OsStr, dir: &Path, args: &[&str]) -> Result<()> {
+    let mut cmd = Command::new(cargo);
+    cmd.args(args)
+        .args(&["--offline", "--quiet"])
+        .current_dir(dir)
+        .env("CARGO_TARGET_DIR", target_dir(dir));
+    config
+        .shell()
+        .verbose(|shell| shell.status("Running", format_args!("{:?}", cmd)))?;
+    let status = cmd.status()?;
+    ensure!(
+        status.success(),
+        "`cargo {}` failed: {}",
+        args.join(" "),
+        status
+    );
+    Ok(())
+}
@@ -0,0 +1,224 @@
+//! Disk usage computation.
+use notify::DebouncedEvent;
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
+    thread,
+};
+
+/// Total size of files under `path` recursively, without following symlinks.
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+    let meta = path.symlink_metadata()?;
+    let mut ret = meta.len();
+    if meta.is_dir() {
+        for entry in fs::read_dir(path)? {
+            ret += dir_size(&entry?.path())?;
+        }
+    }
+    Ok(ret)
+}
+
+/// Like `dir_size`, walking directories with `jobs` threads.
+pub fn dir_size_par(path: &Path, jobs: usize) -> io::Result<u64> {
+    fn add(size: &mut u64, _path: &Path, meta: &fs::Metadata) {
+        *size += meta.len();
+    }
+    Ok(par_walk(path, jobs, add)?.into_iter().sum())
+}
+
+/// Walk `root` and everything under it with `jobs` threads without following symlinks,
+/// and call `visit` with the metadata of each entry on an accumulator of the thread.
+/// Return accumulators of all threads.
+///
+/// Directories are queued and taken by idle threads, so deep and wide trees are both spread
+/// across threads. Metadata is read relative to the open directory, which matters on
+/// spinning disks and network filesystems where each stat is a round trip.
+pub fn par_walk<T: Default + Send + 'static>(
+    root: &Path,
+    jobs: usize,
+    visit: fn(&mut T, &Path, &fs::Metadata),
+) -> io::Result<Vec<T>> {
+    let meta = root.symlink_metadata()?;
+    let mut first = T::default();
+    visit(&mut first, root, &meta);
+    if !meta.is_dir() {
+        return Ok(vec![first]);
+    }
+
+    let queue = Arc::new(Queue {
+        state: Mutex::new(QueueState {
+            dirs: vec![root.to_owned()],
+            busy: 0,
+            error: None,
+        }),
+        ready: Condvar::new(),
+    });
+    let handles = (1..jobs.max(1))
+        .map(|_| {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                let mut acc = T::default();
+                queue.work(visit, &mut acc);
+                acc
+            })
+        })
+        .collect::<Vec<_>>();
+    queue.work(visit, &mut first);
+    let mut ret = vec![first];
+    ret.extend(
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Walking thread panicked")),
+    );
+    match queue.state.lock().unwrap().error.take() {
+        Some(err) => Err(err),
+        None => Ok(ret),
+    }
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+struct QueueState {
+    dirs: Vec<PathBuf>,
+    /// Threads scanning a directory, which may queue more.
+    busy: usize,
+    error: Option<io::Error>,
+}
+
+impl Queue {
+    /// Scan queued directories until all are done, or any fails.
+    fn work<T>(&self, visit: fn(&mut T, &Path, &fs::Metadata), acc: &mut T) {
+        loop {
+            let dir = {
+                let mut state = self.state.lock().unwrap();
+                loop {
+                    if state.error.is_some() {
+                        return;
+                    }
+                    if let Some(dir) = state.dirs.pop() {
+                        state.busy += 1;
+                        break dir;
+                    }
+                    if state.busy == 0 {
+                        return;
+                    }
+                    state = self.ready.wait(state).unwrap();
+                }
+            };
+            let ret = Self::scan(&dir, visit, acc);
+            let mut state = self.state.lock().unwrap();
+            state.busy -= 1;
+            match ret {
+                Ok(subdirs) => state.dirs.extend(subdirs),
+                Err(err) => {
+                    state.error.get_or_insert(err);
+                }
+            }
+            self.ready.notify_all();
+        }
+    }
+
+    /// Visit entries of `dir` and return its subdirectories.
+    fn scan<T>(
+        dir: &Path,
+        visit: fn(&mut T, &Path, &fs::Metadata),
+        acc: &mut T,
+    ) -> io::Result<Vec<PathBuf>> {
+        let mut subdirs = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            // Not following symlinks, like `symlink_metadata`.
+            let meta = entry.metadata()?;
+            visit(acc, &path, &meta);
+            if meta.is_dir() {
+                subdirs.push(path);
+            }
+        }
+        Ok(subdirs)
+    }
+}
+
+/// Sizes of all entries under a directory, kept up to date by filesystem notifications
+/// so that the total size is available without rescanning.
+#[derive(Debug, Default)]
+pub struct Inventory {
+    sizes: BTreeMap<PathBuf, u64>,
+    total: u64,
+}
+
+impl Inventory {
+    pub fn scan(root: &Path) -> io::Result<Self> {
+        let mut ret = Self::default();
+        ret.insert(root)?;
+        Ok(ret)
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Re-account `path` and everything under it.
+    pub fn update(&mut self, path: &Path) -> io::Result<()> {
+        let stale = self
+            .sizes
+            .range(path.to_owned()..)
+            .take_while(|(p, _)| p.starts_with(path))
+            .map(|(p, _)| p.clone())
+            .collect::<Vec<_>>();
+        for p in stale {
+            self.total -= self.sizes.remove(&p).unwrap();
+        }
+        match self.insert(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            ret => ret,
+        }
+    }
+
+    /// Apply a filesystem notification.
+    pub fn handle(&mut self, root: &Path, event: DebouncedEvent) -> io::Result<()> {
+        match event {
+            DebouncedEvent::Create(path)
+            | DebouncedEvent::Write(path)
+            | DebouncedEvent::Remove(path) => self.update(&path),
+            DebouncedEvent::Rename(from, to) => {
+                self.update(&from)?;
+                self.update(&to)
+            }
+            DebouncedEvent::Rescan => {
+                *self = Self::scan(root)?;
+                Ok(())
+            }
+            DebouncedEvent::Error(err, path) => {
+                tracing::debug!("Watch error on {:?}: {}", path, err);
+                self.update(path.as_deref().unwrap_or(root))
+            }
+            DebouncedEvent::NoticeWrite(_)
+            | DebouncedEvent::NoticeRemove(_)
+            | DebouncedEvent::Chmod(_) => Ok(()),
+        }
+    }
+
+    fn insert(&mut self, path: &Path) -> io::Result<()> {
+        let meta = path.symlink_metadata()?;
+        if meta.is_dir() {
+            for entry in fs::read_dir(path)? {
+                match self.insert(&entry?.path()) {
+                    // Removed concurrently. A notification will follow.
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    ret => ret?,
+                }
+            }
+        }
+        self.total += meta.len();
+        if let Some(old) = self.sizes.insert(path.to_owned(), meta.len()) {
+            self.total -= old;
+        }
+        Ok(())
+    }
+}
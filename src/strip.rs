@@ -0,0 +1,107 @@
+//! Stripping debug info from old artifacts, which is much cheaper to regain than removed
+//! artifacts.
+use crate::{ondisk, remove::Remover};
+use anyhow::Result;
+use cargo::Config;
+use std::{
+    collections::HashSet,
+    env,
+    ffi::OsString,
+    fs,
+    path::Path,
+    process::{Command, Stdio},
+    time::{Duration, SystemTime},
+};
+
+/// `$STRIP`, or `llvm-strip` if installed, which handles objects of any target, or `strip`.
+fn strip_program() -> OsString {
+    if let Some(strip) = env::var_os("STRIP") {
+        return strip;
+    }
+    let has_llvm_strip = Command::new("llvm-strip")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map_or(false, |status| status.success());
+    OsString::from(if has_llvm_strip {
+        "llvm-strip"
+    } else {
+        "strip"
+    })
+}
+
+/// Strip debug sections from libraries and binaries in `deps` of the profile directory `dir`
+/// not modified within `max_age`, and invalidate their fingerprints.
+///
+/// Cargo would otherwise reuse stripped artifacts silently. With fingerprints removed,
+/// they are rebuilt only when used again.
+pub fn strip_old(
+    config: &Config,
+    dir: &Path,
+    max_age: Duration,
+    remover: &mut Remover,
+) -> Result<()> {
+    let strip = strip_program();
+    let now = SystemTime::now();
+    let deps_dir = dir.join("deps");
+    let mut invalidated = HashSet::new();
+    for entry in remover.read_dir(&deps_dir)? {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_candidate = name.ends_with(".rlib")
+            || name.ends_with(".so")
+            || name.ends_with(".dylib")
+            || !name.contains('.') && ondisk::is_executable(&path);
+        if !is_candidate || remover.is_removed(&path) {
+            continue;
+        }
+        let meta = fs::symlink_metadata(&path)?;
+        match meta.modified().map(|mtime| now.duration_since(mtime)) {
+            Ok(Ok(age)) if age > max_age => {}
+            _ => continue,
+        }
+        let hash = match ondisk::split_hash(name.split('.').next().unwrap()) {
+            Some((_, hash)) => hash.to_owned(),
+            None => continue,
+        };
+
+        config
+            .shell()
+            .verbose(|s| s.status("Stripping", path.display()))?;
+        if remover.dry_run {
+            invalidated.insert(hash);
+            continue;
+        }
+        // `-S` strips debug info with GNU, LLVM and macOS `strip`, unlike `--strip-debug`.
+        let status = Command::new(&strip).arg("-S").arg(&path).status();
+        match status {
+            Ok(status) if status.success() => {}
+            ret => {
+                config.shell().warn(format_args!(
+                    "Failed to strip {}: {}",
+                    path.display(),
+                    match ret {
+                        Ok(status) => status.to_string(),
+                        Err(err) => err.to_string(),
+                    },
+                ))?;
+                continue;
+            }
+        }
+        let stripped = fs::symlink_metadata(&path)?.len();
+        remover.freed_bytes += meta.len().saturating_sub(stripped);
+        invalidated.insert(hash);
+    }
+
+    for name in ondisk::list_names(&dir.join(".fingerprint"))? {
+        match ondisk::split_hash(&name) {
+            Some((_, hash)) if invalidated.contains(hash) => {
+                let path = dir.join(".fingerprint").join(&name);
+                remover.remove(&path, "fingerprint of stripped artifacts")?;
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
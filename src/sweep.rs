@@ -0,0 +1,545 @@
+//! Categories of files in the target directory besides profile directories.
+use crate::{ondisk, remove::Remover};
+use anyhow::Result;
+use cargo::Config;
+use glob::Pattern;
+use std::{
+    fs,
+    io::{self, Write as _},
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+/// Output directories of `cargo llvm-cov` and common coverage setups.
+const COVERAGE_DIRS: &[&str] = &["coverage", "llvm-cov", "llvm-cov-target"];
+
+/// Remove raw and merged profile data in the target root and entries of coverage output
+/// directories. Entries modified within `max_age` are kept.
+pub fn sweep_coverage(
+    target_dir: &Path,
+    max_age: Option<Duration>,
+    remover: &mut Remover,
+) -> Result<()> {
+    for entry in remover.read_dir(target_dir)? {
+        let path = entry.path();
+        let file_name = entry.file_name();
+        let name = match file_name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if COVERAGE_DIRS.contains(&name) && path.is_dir() {
+            for entry in remover.read_dir(&path)? {
+                let path = entry.path();
+                if is_expired(&path, max_age) {
+                    remover.remove(&path, "coverage output")?;
+                }
+            }
+        } else if (name.ends_with(".profraw") || name.ends_with(".profdata"))
+            && is_expired(&path, max_age)
+        {
+            remover.remove(&path, "coverage profile data")?;
+        }
+    }
+    Ok(())
+}
+
+/// Temporaries of builds and tests in progress are kept for this long.
+const TEMPS_MIN_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// Remove contents of `target/tmp`, the `CARGO_TARGET_TMPDIR` for integration tests and
+/// benches. Cargo creates the directory but never manages its content. Entries with anything
+/// modified within `TEMPS_MIN_AGE` are kept, since tests may be using them.
+pub fn sweep_tmp(target_dir: &Path, remover: &mut Remover) -> Result<()> {
+    let tmp_dir = target_dir.join("tmp");
+    if !tmp_dir.is_dir() {
+        return Ok(());
+    }
+    for entry in remover.read_dir(&tmp_dir)? {
+        let path = entry.path();
+        if is_idle(&path, TEMPS_MIN_AGE) {
+            remover.remove(&path, "test temporary")?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether nothing under `path` is modified within `min_age`. Unreadable entries are never
+/// considered idle.
+fn is_idle(path: &Path, min_age: Duration) -> bool {
+    if !is_expired(path, Some(min_age)) {
+        return false;
+    }
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => match fs::read_dir(path) {
+            Ok(entries) => entries
+                .into_iter()
+                .all(|entry| entry.map_or(false, |entry| is_idle(&entry.path(), min_age))),
+            Err(_) => false,
+        },
+        Ok(_) => true,
+        Err(_) => false,
+    }
+}
+
+/// Remove ICE dumps of rustc, LLVM profile data of `-Z self-profile`, leftovers of `patch`
+/// and stray temporary files in the target directory. Return the number and the total size
+/// of removed files.
+///
+/// Incremental caches, `deps`, `target/tmp` of tests and `OUT_DIR`s of build scripts are
+/// skipped. Files there may be real outputs, which cargo never restores.
+pub fn sweep_temps(target_dir: &Path, remover: &mut Remover) -> Result<(usize, u64)> {
+    fn is_temp(name: &str) -> bool {
+        name.starts_with("rustc-ice-") && name.ends_with(".txt")
+            || [".mm_profdata", ".orig", ".rej", ".tmp"]
+                .iter()
+                .any(|ext| name.ends_with(ext))
+    }
+
+    /// Whether to skip the subdirectory `name` of `dir`.
+    fn is_skipped(dir: &Path, name: &str, is_root: bool) -> bool {
+        name == "incremental"
+            || name == "deps"
+            || is_root && name == "tmp"
+            // `build/{pkg}-{hash}/out`
+            || name == "out"
+                && dir.parent().and_then(|p| p.file_name()).and_then(|p| p.to_str())
+                    == Some("build")
+    }
+
+    fn walk(
+        dir: &Path,
+        is_root: bool,
+        remover: &mut Remover,
+        found: &mut (usize, u64),
+    ) -> Result<()> {
+        for entry in remover.read_dir(dir)? {
+            let path = entry.path();
+            let file_name = entry.file_name();
+            let name = file_name.to_string_lossy();
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(err) => {
+                    remover.tolerate(&path, err)?;
+                    continue;
+                }
+            };
+            if remover.is_removed(&path) {
+                continue;
+            }
+            if meta.is_dir() {
+                if !is_skipped(dir, &name, is_root) {
+                    walk(&path, false, remover, found)?;
+                }
+            } else if is_temp(&name) && is_expired(&path, Some(TEMPS_MIN_AGE)) {
+                remover.remove(&path, "temporary or crash dump")?;
+                found.0 += 1;
+                found.1 += meta.len();
+            }
+        }
+        Ok(())
+    }
+
+    let mut found = (0, 0);
+    walk(target_dir, true, remover, &mut found)?;
+    Ok(found)
+}
+
+/// Remove copies in `artifact_dir`, as made by `--out-dir` or `--artifact-dir` of cargo,
+/// which are not identical to any remaining uplifted file in profile directories, ie. whose
+/// sources are collected or rebuilt since. Return the number of removed copies.
+///
+/// Only files named after an uplifted file, existing or removed in this run, are copies.
+/// Anything else in the directory is not produced by cargo and left untouched.
+pub fn sweep_artifact_dir(
+    target_dir: &Path,
+    artifact_dir: &Path,
+    remover: &mut Remover,
+) -> Result<usize> {
+    let profile_dirs = ondisk::profile_dirs(target_dir)?;
+    let mut count = 0;
+    for entry in remover.read_dir(artifact_dir)? {
+        let path = entry.path();
+        if !entry.file_type().map_or(false, |ty| ty.is_file()) {
+            continue;
+        }
+        let uplifted = profile_dirs
+            .iter()
+            .map(|dir| dir.join(entry.file_name()))
+            .filter(|uplifted| remover.is_removed(uplifted) || uplifted.is_file())
+            .collect::<Vec<_>>();
+        if uplifted.is_empty() {
+            continue;
+        }
+        let is_current = uplifted.iter().any(|uplifted| {
+            !remover.is_removed(uplifted) && is_same_file(&path, uplifted).unwrap_or(false)
+        });
+        if !is_current {
+            remover.remove(&path, "stale copy in the artifact directory")?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Whether `a` and `b` are hard links of the same file, or have the same content.
+fn is_same_file(a: &Path, b: &Path) -> io::Result<bool> {
+    let (meta_a, meta_b) = (fs::metadata(a)?, fs::metadata(b)?);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        if (meta_a.dev(), meta_a.ino()) == (meta_b.dev(), meta_b.ino()) {
+            return Ok(true);
+        }
+    }
+    Ok(meta_a.len() == meta_b.len() && fs::read(a)? == fs::read(b)?)
+}
+
+/// Remove the target directory of RLS and save-analysis data of profiles, which are only
+/// generated by legacy toolchains.
+pub fn sweep_legacy(target_dir: &Path, remover: &mut Remover) -> Result<()> {
+    let rls_dir = target_dir.join("rls");
+    if rls_dir.is_dir() {
+        remover.remove(&rls_dir, "target directory of RLS")?;
+    }
+    for dir in ondisk::profile_dirs(target_dir)? {
+        let path = dir.join("deps").join("save-analysis");
+        if path.is_dir() && !remover.is_removed(&path) {
+            remover.remove(&path, "save-analysis data")?;
+        }
+    }
+    Ok(())
+}
+
+/// Temporary files left when lock files of cargo are replaced, in profile directories,
+/// `target/package` and the cargo home. Lock files themselves, including `.package-cache-mutate`
+/// of newer cargos, are never matched.
+const LOCK_LEFTOVERS: &[&str] = &[".cargo-lock.tmp", ".package-cache.tmp"];
+
+/// Remove leftovers of lock files which no process holds. Return the number of removed files.
+///
+/// Lock files themselves, like `.cargo-lock`, are never removed. A cargo which opened one and
+/// is waiting on it would hold the unlinked file, while the next cargo locks a new one, and
+/// both would build concurrently. Removing them frees nothing anyway.
+pub fn sweep_locks(target_dir: &Path, cargo_home: &Path, remover: &mut Remover) -> Result<usize> {
+    let mut dirs = ondisk::profile_dirs(target_dir)?;
+    dirs.push(target_dir.join("package"));
+    dirs.push(cargo_home.to_owned());
+    let mut count = 0;
+    for dir in dirs {
+        for name in ondisk::list_names(&dir)? {
+            let path = dir.join(&name);
+            if !LOCK_LEFTOVERS.contains(&&*name) || remover.is_removed(&path) {
+                continue;
+            }
+            // Leftovers are never locked by cargo, but other tools may still hold them.
+            let _guard = match try_lock(&path) {
+                Ok(Some(guard)) => guard,
+                Ok(None) => {
+                    tracing::debug!(path = %path.display(), "lock is held");
+                    continue;
+                }
+                Err(err) => {
+                    remover.tolerate(&path, err)?;
+                    continue;
+                }
+            };
+            remover.remove(&path, "leftover of a lock file")?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Try to lock `path` exclusively as cargo does, and return the guard if no one holds it.
+#[cfg(unix)]
+pub fn try_lock(path: &Path) -> io::Result<Option<fs::File>> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = fs::File::open(path)?;
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == 0 {
+        return Ok(Some(file));
+    }
+    let err = io::Error::last_os_error();
+    if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+        return Ok(None);
+    }
+    Err(err)
+}
+
+/// Locks cannot be checked without `LockFileEx`, so they are always considered held.
+#[cfg(not(unix))]
+pub fn try_lock(_path: &Path) -> io::Result<Option<fs::File>> {
+    Ok(None)
+}
+
+/// Subdirectories of profile directories which may be left with empty directories.
+const PRUNABLE_DIRS: &[&str] = &["deps", "build", ".fingerprint", "examples", "incremental"];
+
+/// Remove empty directories bottom-up under subdirectories of each profile directory,
+/// treating removed entries as absent. Return the number of removed directories.
+pub fn prune_empty_dirs(target_dir: &Path, remover: &mut Remover) -> Result<usize> {
+    let mut count = 0;
+    for dir in ondisk::profile_dirs(target_dir)? {
+        for name in PRUNABLE_DIRS {
+            let path = dir.join(name);
+            if path.is_dir() && !remover.is_removed(&path) {
+                prune_empty_children(&path, remover, &mut count)?;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Remove empty subdirectories of `dir` recursively, and return whether `dir` is empty
+/// afterwards. Unreadable directories are never considered empty.
+fn prune_empty_children(dir: &Path, remover: &mut Remover, count: &mut usize) -> Result<bool> {
+    let entries = match fs::read_dir(dir).and_then(|iter| iter.collect::<io::Result<Vec<_>>>()) {
+        Ok(entries) => entries,
+        Err(err) => {
+            remover.tolerate(dir, err)?;
+            return Ok(false);
+        }
+    };
+    let mut is_empty = true;
+    for entry in entries {
+        let path = entry.path();
+        if remover.is_removed(&path) {
+            continue;
+        }
+        let is_dir = entry.file_type().map_or(false, |ty| ty.is_dir());
+        if is_dir && prune_empty_children(&path, remover, count)? {
+            remover.remove(&path, "empty directory")?;
+            *count += 1;
+        } else {
+            is_empty = false;
+        }
+    }
+    Ok(is_empty)
+}
+
+/// Fingerprint of the toolchain which generated `target/doc`, written by cargo.
+const RUSTDOC_FINGERPRINT: &str = ".rustdoc_fingerprint.json";
+
+/// Remove documentations generated by a toolchain other than those of `rustc_vvs`, outputs
+/// of `rustc -vV`, along with the stale fingerprint. Return the number of removed `doc`
+/// directories.
+///
+/// Cargo wipes them on the next doc build anyway, which is surprising when it happens
+/// long after switching toolchains. An unreadable fingerprint is removed alone, which cargo
+/// simply regenerates.
+pub fn sweep_stale_doc(
+    target_dir: &Path,
+    rustc_vvs: &[String],
+    remover: &mut Remover,
+) -> Result<usize> {
+    let fingerprint = target_dir.join(RUSTDOC_FINGERPRINT);
+    let content = match fs::read_to_string(&fingerprint) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err.into()),
+    };
+    let fingerprint_vv = serde_json::from_str::<serde_json::Value>(&content)
+        .ok()
+        .and_then(|v| Some(v.get("rustc_vv")?.as_str()?.to_owned()));
+    let fingerprint_vv = match fingerprint_vv {
+        Some(vv) => vv,
+        None => {
+            remover.remove(&fingerprint, "unreadable rustdoc fingerprint")?;
+            return Ok(0);
+        }
+    };
+    if rustc_vvs.contains(&fingerprint_vv) {
+        return Ok(0);
+    }
+
+    // Cargo cleans documentations of the host and all target triples.
+    let mut doc_dirs = vec![target_dir.join("doc")];
+    for entry in remover.read_dir(target_dir)? {
+        let path = entry.path();
+        if is_profile_or_triple_dir(&path) {
+            doc_dirs.push(path.join("doc"));
+        }
+    }
+    let mut count = 0;
+    for dir in doc_dirs {
+        if dir.is_dir() && !remover.is_removed(&dir) {
+            remover.remove(&dir, "documentation of a different toolchain")?;
+            count += 1;
+        }
+    }
+    remover.remove(&fingerprint, "stale rustdoc fingerprint")?;
+    Ok(count)
+}
+
+/// Remove whole profile directories not used within `max_age`.
+pub fn prune_unused_profiles(
+    config: &Config,
+    target_dir: &Path,
+    max_age: Duration,
+    remover: &mut Remover,
+) -> Result<()> {
+    let now = SystemTime::now();
+    for dir in ondisk::profile_dirs(target_dir)? {
+        let age = match now.duration_since(last_used(&dir)?) {
+            Ok(age) if age > max_age => age,
+            _ => continue,
+        };
+        let display_dir = dir.strip_prefix(target_dir).unwrap_or(&dir);
+        let display_age = humantime::format_duration(Duration::from_secs(age.as_secs()));
+        config.shell().status(
+            "Pruning",
+            format_args!("{}, last used {} ago", display_dir.display(), display_age),
+        )?;
+        remover.remove(&dir, format_args!("profile unused for {}", display_age))?;
+    }
+    Ok(())
+}
+
+/// The last time a profile directory is used, by the latest modified time of
+/// `.cargo-lock` and fingerprints.
+fn last_used(dir: &Path) -> io::Result<SystemTime> {
+    let mut ret = ondisk::latest_mtime(dir)?;
+    let fingerprint_dir = dir.join(".fingerprint");
+    if fingerprint_dir.is_dir() {
+        for entry in fs::read_dir(&fingerprint_dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                ret = ret.max(ondisk::latest_mtime(&path)?);
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Whether `path` is not modified within `max_age`. Always true if `max_age` is not set.
+pub fn is_expired(path: &Path, max_age: Option<Duration>) -> bool {
+    let max_age = match max_age {
+        Some(max_age) => max_age,
+        None => return true,
+    };
+    fs::symlink_metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|mtime| SystemTime::now().duration_since(mtime).ok())
+        .map_or(false, |age| age > max_age)
+}
+
+/// Entries in the target root managed by cargo, common tools or cargo-gc itself,
+/// besides profile and target triple directories.
+const KNOWN_ROOT_ENTRIES: &[&str] = &[
+    ".cargo-gc-branches",
+    ".cargo-gc-last-run",
+    ".cargo-gc-owners",
+    ".rustc_info.json",
+    RUSTDOC_FINGERPRINT,
+    "CACHEDIR.TAG",
+    "criterion",
+    "doc",
+    "nextest",
+    "package",
+    "tmp",
+];
+
+/// List entries in the target root unknown to cargo and not matching `keep`, and remove them
+/// after confirmation, or without it if `yes` is set.
+pub fn sweep_unknown(
+    config: &Config,
+    target_dir: &Path,
+    keep: &[Pattern],
+    yes: bool,
+    remover: &mut Remover,
+) -> Result<()> {
+    let mut unknown = Vec::new();
+    for entry in remover.read_dir(target_dir)? {
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let is_known = KNOWN_ROOT_ENTRIES.contains(&&*name)
+            || COVERAGE_DIRS.contains(&&*name)
+            || name.ends_with(".profraw")
+            || name.ends_with(".profdata")
+            || keep.iter().any(|pat| pat.matches(&name))
+            || is_profile_or_triple_dir(&path)
+            || remover.is_removed(&path);
+        if !is_known {
+            unknown.push(path);
+        }
+    }
+    if unknown.is_empty() {
+        return Ok(());
+    }
+
+    config.shell().warn(format_args!(
+        "{} unknown entries in the target directory:{}",
+        unknown.len(),
+        unknown
+            .iter()
+            .map(|p| format!("\n  {}", p.display()))
+            .collect::<String>(),
+    ))?;
+    if !yes && !remover.dry_run && !confirm("Remove them?")? {
+        return Ok(());
+    }
+    for path in unknown {
+        remover.remove(&path, "unknown entry in the target directory")?;
+    }
+    Ok(())
+}
+
+/// Whether `path` is a profile directory, or a target triple directory containing them.
+fn is_profile_or_triple_dir(path: &Path) -> bool {
+    if path.join(".fingerprint").is_dir() {
+        return true;
+    }
+    fs::read_dir(path).map_or(false, |iter| {
+        iter.filter_map(|entry| entry.ok())
+            .any(|entry| entry.path().join(".fingerprint").is_dir())
+    })
+}
+
+/// Ask a yes/no question on the terminal. Default to no.
+fn confirm(question: &str) -> io::Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn artifact_dir_copies() {
+        let dir = env::temp_dir().join(format!("cargo-gc-artifact-dir-{}", std::process::id()));
+        let (profile_dir, artifact_dir) = (dir.join("target").join("debug"), dir.join("out"));
+        fs::create_dir_all(profile_dir.join(".fingerprint")).unwrap();
+        fs::create_dir_all(&artifact_dir).unwrap();
+        let files = [
+            (profile_dir.join("current"), "new"),
+            (profile_dir.join("rebuilt"), "new"),
+            (profile_dir.join("collected"), "old"),
+            (artifact_dir.join("current"), "new"),
+            (artifact_dir.join("rebuilt"), "old"),
+            (artifact_dir.join("collected"), "old"),
+            (artifact_dir.join("README.md"), "unrelated"),
+        ];
+        for (path, content) in &files {
+            fs::write(path, content).unwrap();
+        }
+
+        let config = Config::default().unwrap();
+        let mut remover = Remover::new(&config, false);
+        remover
+            .remove(&profile_dir.join("collected"), "unreachable")
+            .unwrap();
+        let count = sweep_artifact_dir(&dir.join("target"), &artifact_dir, &mut remover).unwrap();
+        let mut remaining = ondisk::list_names(&artifact_dir).unwrap();
+        remaining.sort();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(count, 2);
+        assert_eq!(remaining, ["README.md", "current"]);
+    }
+}
@@ -0,0 +1,63 @@
+//! Breakdown of time spent in each phase, for `--profile-self`.
+use cargo::{CargoResult, Config};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+thread_local! {
+    /// Phase -> (total duration, count). `None` if not enabled.
+    static PHASES: RefCell<Option<BTreeMap<String, (Duration, u64)>>> = RefCell::new(None);
+}
+
+/// Start recording phases on the current thread.
+pub fn enable() {
+    PHASES.with(|phases| *phases.borrow_mut() = Some(BTreeMap::new()));
+}
+
+/// Run `f` and account its duration to `phase`, if enabled.
+pub fn measure<T>(phase: &str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let ret = f();
+    record(phase, start.elapsed());
+    ret
+}
+
+/// Account `elapsed` to `phase`, if enabled.
+pub fn record(phase: &str, elapsed: Duration) {
+    PHASES.with(|phases| {
+        if let Some(phases) = &mut *phases.borrow_mut() {
+            let (total, count) = phases.entry(phase.to_owned()).or_default();
+            *total += elapsed;
+            *count += 1;
+        }
+    });
+}
+
+/// Print recorded phases, the slowest first. Nothing is printed if not enabled.
+pub fn report(config: &Config, total: Duration) -> CargoResult<()> {
+    let mut phases = match PHASES.with(|phases| phases.borrow_mut().take()) {
+        Some(phases) => phases.into_iter().collect::<Vec<_>>(),
+        None => return Ok(()),
+    };
+    phases.sort_by_key(|(_, (elapsed, _))| std::cmp::Reverse(*elapsed));
+    let mut shell = config.shell();
+    for (phase, (elapsed, count)) in phases {
+        shell.status(
+            "Timing",
+            format_args!(
+                "{:>9.3}s {:>5.1}% {} ({} times)",
+                elapsed.as_secs_f64(),
+                elapsed.as_secs_f64() / total.as_secs_f64().max(1e-9) * 100.0,
+                phase,
+                count,
+            ),
+        )?;
+    }
+    shell.status(
+        "Timing",
+        format_args!("{:>9.3}s total", total.as_secs_f64()),
+    )?;
+    Ok(())
+}
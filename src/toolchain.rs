@@ -0,0 +1,29 @@
+//! Collection under other rustup toolchains.
+use anyhow::{ensure, Context as _, Result};
+use std::{collections::HashMap, env, process::Command};
+
+/// Environment variables to use `rustc` and `rustdoc` of rustup toolchain `toolchain`.
+/// Different rustc versions produce different metadata hashes.
+pub fn env(toolchain: &str) -> Result<HashMap<String, String>> {
+    let mut ret = env::vars().collect::<HashMap<_, _>>();
+    for (var, tool) in &[("RUSTC", "rustc"), ("RUSTDOC", "rustdoc")] {
+        ret.insert(var.to_string(), which(toolchain, tool)?);
+    }
+    ret.insert("RUSTUP_TOOLCHAIN".into(), toolchain.into());
+    Ok(ret)
+}
+
+fn which(toolchain: &str, tool: &str) -> Result<String> {
+    let output = Command::new("rustup")
+        .args(&["which", "--toolchain", toolchain, tool])
+        .output()
+        .context("Failed to run `rustup`")?;
+    ensure!(
+        output.status.success(),
+        "Cannot find `{}` of toolchain `{}`: {}",
+        tool,
+        toolchain,
+        String::from_utf8_lossy(&output.stderr).trim(),
+    );
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
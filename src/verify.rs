@@ -0,0 +1,105 @@
+//! Verification that nothing is rebuilt after GC.
+use crate::ondisk;
+use anyhow::{bail, ensure, Result};
+use cargo::Config;
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime},
+};
+
+/// Run `command`, or `cargo check` of the workspace `manifest` with the cargo executable
+/// `cargo` by default, and fail if any unit in `existing`, fingerprint directories listed by
+/// `fingerprint_dirs` before GC, is rebuilt in the target directory.
+pub fn verify_after(
+    config: &Config,
+    command: Option<&str>,
+    cargo: &OsStr,
+    manifest: Option<&Path>,
+    target_dir: &Path,
+    existing: &HashSet<PathBuf>,
+) -> Result<()> {
+    let mut cmd = match command {
+        Some(command) => {
+            let mut words = command.split_whitespace();
+            let program = match words.next() {
+                Some(program) => program,
+                None => bail!("Empty verification command"),
+            };
+            let mut cmd = Command::new(program);
+            cmd.args(words);
+            cmd
+        }
+        None => {
+            let mut cmd = Command::new(cargo);
+            cmd.args(&["check", "--workspace", "--all-targets"]);
+            if let Some(manifest) = manifest {
+                cmd.arg("--manifest-path").arg(manifest);
+            }
+            cmd.arg("--target-dir").arg(target_dir);
+            cmd
+        }
+    };
+
+    // Leave a margin for coarse mtime granularity.
+    let start = SystemTime::now() - Duration::from_secs(1);
+    config
+        .shell()
+        .status("Verifying", format_args!("{:?}", cmd))?;
+    let status = cmd.status()?;
+    ensure!(status.success(), "Verification command failed: {}", status);
+
+    let rebuilt = rebuilt_units(target_dir, start, existing)?;
+    if !rebuilt.is_empty() {
+        bail!(
+            "{} units are rebuilt after GC:{}",
+            rebuilt.len(),
+            rebuilt
+                .iter()
+                .map(|p| format!("\n  {}", p.display()))
+                .collect::<String>(),
+        );
+    }
+    config
+        .shell()
+        .status("Verified", "no units are rebuilt after GC")?;
+    Ok(())
+}
+
+/// Fingerprint directories of all profiles, relative to the target directory.
+pub fn fingerprint_dirs(target_dir: &Path) -> Result<HashSet<PathBuf>> {
+    let mut ret = HashSet::new();
+    for dir in ondisk::profile_dirs(target_dir)? {
+        for entry in fs::read_dir(dir.join(".fingerprint"))? {
+            let path = entry?.path();
+            if path.is_dir() {
+                ret.insert(path.strip_prefix(target_dir).unwrap_or(&path).to_owned());
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Fingerprint directories in `existing` modified since `since`. Units built for the first
+/// time, eg. of targets never built before GC, are not rebuilds.
+pub fn rebuilt_units(
+    target_dir: &Path,
+    since: SystemTime,
+    existing: &HashSet<PathBuf>,
+) -> Result<Vec<PathBuf>> {
+    let mut ret = Vec::new();
+    for path in fingerprint_dirs(target_dir)? {
+        if !existing.contains(&path) {
+            continue;
+        }
+        let path = target_dir.join(path);
+        if ondisk::latest_mtime(&path)? >= since {
+            ret.push(path);
+        }
+    }
+    ret.sort();
+    Ok(ret)
+}
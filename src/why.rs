@@ -0,0 +1,97 @@
+//! Attribute disk usage of a profile directory to packages and workspace members.
+use crate::collect::Reachable;
+use bytesize::ByteSize;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashSet},
+    io,
+    path::{Path, PathBuf},
+};
+
+/// Disk usage of a package built with a feature set.
+#[derive(Debug, Default)]
+pub struct Usage {
+    pub size: u64,
+    /// Workspace members depending on it, directly or transitively.
+    pub pulled_by: BTreeSet<String>,
+}
+
+/// Sum up sizes of reachable units in the profile directory `dir`, grouped by
+/// `(package id, features)`.
+pub fn attribute(
+    dir: &Path,
+    reachable: &Reachable,
+) -> io::Result<BTreeMap<(String, Vec<String>), Usage>> {
+    let mut counted = HashSet::<PathBuf>::new();
+    let mut ret = BTreeMap::<_, Usage>::new();
+    for node in &reachable.graph {
+        let mut paths = node
+            .deps_files
+            .iter()
+            .map(|name| dir.join("deps").join(name))
+            .collect::<Vec<_>>();
+        paths.push(dir.join(".fingerprint").join(&node.pkg_dir));
+        if node.is_custom_build {
+            paths.push(dir.join("build").join(&node.pkg_dir));
+        }
+        let mut size = 0;
+        for path in paths {
+            if path.exists() && counted.insert(path.clone()) {
+                size += crate::size::dir_size(&path)?;
+            }
+        }
+        ret.entry((node.package_id.clone(), node.features.clone()))
+            .or_default()
+            .size += size;
+    }
+
+    for root in reachable.graph.iter().filter(|node| node.is_root) {
+        let member = root
+            .package_id
+            .split(' ')
+            .next()
+            .unwrap_or(&root.package_id);
+        let mut visited = HashSet::new();
+        let mut stack = root.deps.clone();
+        while let Some(idx) = stack.pop() {
+            if !visited.insert(idx) {
+                continue;
+            }
+            let node = &reachable.graph[idx];
+            if let Some(usage) = ret.get_mut(&(node.package_id.clone(), node.features.clone())) {
+                usage.pulled_by.insert(member.to_owned());
+            }
+            stack.extend(&node.deps);
+        }
+    }
+    Ok(ret)
+}
+
+/// Format the largest `top` entries of `usages`.
+pub fn report(usages: &BTreeMap<(String, Vec<String>), Usage>, top: usize) -> String {
+    let mut usages = usages.iter().collect::<Vec<_>>();
+    usages.sort_by(|(_, a), (_, b)| b.size.cmp(&a.size));
+    let mut out = String::new();
+    for ((package_id, features), usage) in usages.into_iter().take(top) {
+        out += &format!(
+            "{:>10}  {}",
+            ByteSize(usage.size).to_string_as(true),
+            package_id
+        );
+        if !features.is_empty() {
+            out += &format!(" [features: {}]", features.join(", "));
+        }
+        if !usage.pulled_by.is_empty() {
+            out += &format!(
+                ", pulled in by {}",
+                usage
+                    .pulled_by
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            );
+        }
+        out += "\n";
+    }
+    out
+}